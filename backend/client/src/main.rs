@@ -1,11 +1,81 @@
 use protocol::{Packet, StringKey, Value};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
 
+#[allow(clippy::upper_case_acronyms)]
 type PCT = Packet<StringKey>;
 
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Connects to the unix-socket at RMBER_SOCKET if it is set, otherwise over TCP.
+/// Connects with TLS when RMBER_TLS_CA is set. RMBER_TLS_CERT and RMBER_TLS_KEY add a client-certificate.
+async fn connect(address: &str) -> Box<dyn Stream> {
+    #[cfg(unix)]
+    if let Ok(path) = std::env::var("RMBER_SOCKET") {
+        return Box::new(tokio::net::UnixStream::connect(path).await.unwrap());
+    }
+
+    let stream = TcpStream::connect(address).await.unwrap();
+
+    let ca = match std::env::var("RMBER_TLS_CA") {
+        Ok(ca) => ca,
+        Err(_) => return Box::new(stream),
+    };
+
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca).unwrap() {
+        roots.add(cert.unwrap()).unwrap();
+    }
+
+    let config = ClientConfig::builder().with_root_certificates(roots);
+    let config = match (std::env::var("RMBER_TLS_CERT"), std::env::var("RMBER_TLS_KEY")) {
+        (Ok(cert), Ok(key)) => {
+            let certs = CertificateDer::pem_file_iter(cert)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let key = PrivateKeyDer::from_pem_file(key).unwrap();
+
+            config.with_client_auth_cert(certs, key).unwrap()
+        }
+        _ => config.with_no_client_auth(),
+    };
+
+    let name = std::env::var("RMBER_TLS_NAME").unwrap_or_else(|_| String::from("localhost"));
+    let name = ServerName::try_from(name).unwrap();
+
+    let connector = TlsConnector::from(Arc::new(config));
+
+    Box::new(connector.connect(name, stream).await.unwrap())
+}
+
+async fn send(stream: &mut Box<dyn Stream>, packet: PCT) {
+    packet.write_to(stream).await.unwrap();
+    stream.flush().await.unwrap();
+}
+
 #[tokio::main]
 async fn main() {
-    let mut stream = TcpStream::connect("127.0.0.1:8080").await.unwrap();
+    let mut stream = connect("127.0.0.1:8080").await;
+
+    // Only needed when the server has a credentials-file. Leave RMBER_USER unset for tokens.
+    if let Ok(secret) = std::env::var("RMBER_SECRET") {
+        let auth = PCT::Authenticate {
+            user: std::env::var("RMBER_USER").unwrap_or_default(),
+            secret,
+        };
+        send(&mut stream, auth).await;
+
+        assert_eq!(PCT::Ok {}, PCT::read_from(&mut stream).await.unwrap());
+    }
 
     let schema = "
         first_namespace {
@@ -17,14 +87,14 @@ async fn main() {
     let schema = PCT::RegisterSchema {
         schema: String::from(schema),
     };
-    schema.write_to(&mut stream).await.unwrap();
+    send(&mut stream, schema).await;
 
     assert_eq!(PCT::Ok {}, PCT::read_from(&mut stream).await.unwrap());
 
     let sub = PCT::Subscribe {
         id: StringKey::new("first_namespace/some_value").unwrap(),
     };
-    sub.write_to(&mut stream).await.unwrap();
+    send(&mut stream, sub).await;
 
     assert_eq!(PCT::Ok {}, PCT::read_from(&mut stream).await.unwrap());
 
@@ -35,7 +105,7 @@ async fn main() {
             id: StringKey::new("first_namespace/some_value").unwrap(),
             new_value: Value::I32(i as i32),
         };
-        update.write_to(&mut stream).await.unwrap();
+        send(&mut stream, update).await;
 
         assert_eq!(PCT::Ok {}, PCT::read_from(&mut stream).await.unwrap());
         assert_eq!(
@@ -1,4 +1,7 @@
 pub const PACKET_INVALID_TYPE_ERR: u32 = 1000;
 pub const PACKET_SUBSCRIPTION_ERR: u32 = 1001;
 pub const PACKET_UPDATE_ERR: u32 = 1002;
-pub const PACKET_SCHEMA_ERR: u32 = 1003;
\ No newline at end of file
+pub const PACKET_SCHEMA_ERR: u32 = 1003;
+pub const PACKET_AUTH_ERR: u32 = 1004;
+pub const PACKET_UNAUTHENTICATED_ERR: u32 = 1005;
+pub const PACKET_PERMISSION_ERR: u32 = 1006;
\ No newline at end of file
@@ -65,7 +65,7 @@ impl std::fmt::Display for StringKey {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct RawKey<const LEN: usize>([u8; LEN]);
 
 impl<const LEN: usize> RawKey<LEN> {
@@ -75,7 +75,7 @@ impl<const LEN: usize> RawKey<LEN> {
 
         match key.try_fill(&mut rng) {
             Ok(_) => Ok(key),
-            Err(e) => Err(Error::new(ErrorKind::Other, e)),
+            Err(e) => Err(Error::other(e)),
         }
     }
 }
@@ -99,9 +99,7 @@ impl<const LEN: usize> Key for RawKey<LEN> {
             ));
         }
 
-        let clone = key.clone();
-
-        Ok(RawKey(clone.try_into().unwrap()))
+        Ok(RawKey(key.try_into().unwrap()))
     }
 
     fn as_slice(&self) -> &[u8] {
@@ -1,8 +1,10 @@
+use super::value::encode_str;
 use super::{Key, Value};
+use bytes::{BufMut, Bytes, BytesMut};
 use std::io::{Error, ErrorKind};
 use std::marker::Unpin;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_byteorder::{AsyncReadBytesExt, AsyncWriteBytesExt};
+use tokio_byteorder::AsyncReadBytesExt;
 
 #[derive(Debug, PartialEq)]
 pub enum Packet<TKey>
@@ -28,34 +30,69 @@ where
         message: String,
     },
     Ok {},
+    /// Authenticate the connection. `user` is empty when `secret` is a bearer-token.
+    Authenticate {
+        user: String,
+        secret: String,
+    },
+    /// Sent by the server before it closes the connection because it shuts down.
+    Shutdown {},
 }
 
 impl<TKey: Key> Packet<TKey> {
+    /// The packet-type in snake-case, e.g. for logs and metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Packet::RegisterSchema { .. } => "register_schema",
+            Packet::Subscribe { .. } => "subscribe",
+            Packet::Update { .. } => "update",
+            Packet::Error { .. } => "error",
+            Packet::Ok {} => "ok",
+            Packet::Authenticate { .. } => "authenticate",
+            Packet::Shutdown {} => "shutdown",
+        }
+    }
+
     pub async fn write_to<TTarget>(self, target: &mut TTarget) -> Result<(), Error>
     where
         TTarget: AsyncWrite + Unpin + Send,
     {
-        target.write_u8((&self).into()).await?;
+        tokio::io::AsyncWriteExt::write_all(target, &self.to_bytes()).await
+    }
+
+    /// Encodes the packet once, e.g. to write the same one to many connections.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut target = BytesMut::new();
+        self.encode(&mut target);
+
+        target.freeze()
+    }
+
+    /// Appends the packet like `write_to` writes it.
+    pub fn encode(&self, target: &mut BytesMut) {
+        target.put_u8(self.into());
 
         match self {
             Packet::Subscribe { id } => {
-                write_key(target, &id).await?;
+                encode_key(target, id);
             }
             Packet::Update { id, new_value } => {
-                write_key(target, &id).await?;
-                new_value.write_to(target).await?;
+                encode_key(target, id);
+                new_value.encode(target);
             }
             Packet::Error { code, message } => {
-                Value::U32(code).write_to(target).await?;
-                Value::String(message).write_to(target).await?;
+                Value::U32(*code).encode(target);
+                encode_str(target, message);
             }
-            Packet::Ok {} => {}
+            Packet::Ok {} | Packet::Shutdown {} => {}
             Packet::RegisterSchema { schema } => {
-                Value::String(schema).write_to(target).await?;
+                encode_str(target, schema);
+            }
+            Packet::Authenticate { user, secret } => {
+                encode_str(target, user);
+                encode_str(target, secret);
             }
         };
-
-        Ok(())
     }
 
     pub async fn read_from<TSource>(source: &mut TSource) -> Result<Self, Error>
@@ -100,23 +137,41 @@ impl<TKey: Key> Packet<TKey> {
                     _ => Err(Error::new(ErrorKind::InvalidData, "Invalid schema-type")),
                 }
             }
+            7 => {
+                let content = (
+                    Value::read_from(source).await?,
+                    Value::read_from(source).await?,
+                );
+
+                match content {
+                    (Value::String(user), Value::String(secret)) => {
+                        Ok(Packet::Authenticate { user, secret })
+                    }
+                    _ => Err(Error::new(ErrorKind::InvalidData, "Invalid credentials-type")),
+                }
+            }
+            8 => Ok(Packet::Shutdown {}),
             _ => Err(Error::new(ErrorKind::InvalidData, "Invalid packet-type")),
         }
     }
 }
 
-async fn write_key<TTarget, TKey>(target: &mut TTarget, key: &TKey) -> Result<(), Error>
-where
-    TTarget: AsyncWrite + Unpin,
-    TKey: Key,
-{
-    let data = key.as_slice();
-    let len = data.len() as u8;
+/// Encodes an update like `Packet::Update`, without owning the key and the value.
+pub fn encode_update<TKey: Key>(id: &TKey, new_value: &Value) -> Bytes {
+    let mut target = BytesMut::with_capacity(2 + id.as_slice().len() + new_value.encoded_len());
 
-    target.write_u8(len).await?;
-    tokio::io::AsyncWriteExt::write_all(target, data).await?;
+    target.put_u8(2);
+    encode_key(&mut target, id);
+    new_value.encode(&mut target);
 
-    Ok(())
+    target.freeze()
+}
+
+fn encode_key<TKey: Key>(target: &mut BytesMut, key: &TKey) {
+    let data = key.as_slice();
+
+    target.put_u8(data.len() as u8);
+    target.put_slice(data);
 }
 
 async fn read_key<TSource, TKey>(source: &mut TSource) -> Result<TKey, Error>
@@ -152,6 +207,8 @@ impl<T: Key> From<&Packet<T>> for u8 {
             Packet::Error { code: _, message: _ } => 4,
             Packet::Ok {} => 5,
             Packet::RegisterSchema { schema: _ } => 6,
+            Packet::Authenticate { user: _, secret: _ } => 7,
+            Packet::Shutdown {} => 8,
         }
     }
 }
@@ -161,15 +218,15 @@ mod tests {
     use super::*;
     use crate::StringKey;
 
-    #[tokio::test]
-    async fn write_key_works() {
+    #[test]
+    fn encode_key_works() {
         let key = StringKey::new("test").unwrap();
-        let mut cursor = std::io::Cursor::new(vec![0u8; 100]);
+        let mut target = BytesMut::new();
 
-        write_key(&mut cursor, &key).await.unwrap();
+        encode_key(&mut target, &key);
 
-        assert_eq!(&cursor.get_ref()[0..1], &[4]);
-        assert_eq!(&cursor.get_ref()[1..5], String::from("test").as_bytes());
+        assert_eq!(&target[0..1], &[4]);
+        assert_eq!(&target[1..5], String::from("test").as_bytes());
     }
 
     #[tokio::test]
@@ -259,6 +316,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn encode_update_matches_packet() {
+        let id = StringKey::new("pointid").unwrap();
+        let new_value = Value::String(String::from("value"));
+
+        let encoded = encode_update(&id, &new_value);
+
+        assert_eq!(encoded, Packet::Update { id, new_value }.to_bytes());
+        assert_eq!(encoded.len(), 19);
+    }
+
     #[tokio::test]
     async fn deserialize_subscribe_packet() {
         let data = vec![
@@ -322,4 +390,44 @@ mod tests {
             }
         );
     }
+
+    #[tokio::test]
+    async fn authenticate_packet_roundtrip() {
+        let packet = Packet::<StringKey>::Authenticate {
+            user: String::from("user"),
+            secret: String::from("secret"),
+        };
+
+        let mut target = std::io::Cursor::new(vec![]);
+        packet.write_to(&mut target).await.unwrap();
+
+        assert_eq!(target.get_ref()[0], 7);
+
+        target.set_position(0);
+        let packet = Packet::<StringKey>::read_from(&mut target).await.unwrap();
+
+        assert_eq!(
+            packet,
+            Packet::<StringKey>::Authenticate {
+                user: String::from("user"),
+                secret: String::from("secret"),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_packet_roundtrip() {
+        let mut target = std::io::Cursor::new(vec![]);
+        Packet::<StringKey>::Shutdown {}
+            .write_to(&mut target)
+            .await
+            .unwrap();
+
+        assert_eq!(target.get_ref(), &[8]);
+
+        target.set_position(0);
+        let packet = Packet::<StringKey>::read_from(&mut target).await.unwrap();
+
+        assert_eq!(packet, Packet::<StringKey>::Shutdown {});
+    }
 }
@@ -1,5 +1,6 @@
 use super::Error;
-use tokio_byteorder::{BigEndian, AsyncReadBytesExt, AsyncWriteBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio_byteorder::{BigEndian, AsyncReadBytesExt};
 use tokio::io::{AsyncRead, AsyncWrite};
 use std::{io::ErrorKind, marker::Unpin};
 
@@ -27,43 +28,57 @@ pub enum Value {
 }
 
 impl Value {
-    pub fn as_span(&self) -> &[u8] {
-        todo!();
+    /// The value as `write_to` writes it, encoded without awaiting, e.g. for the store.
+    pub fn as_span(&self) -> Bytes {
+        let mut target = BytesMut::with_capacity(self.encoded_len());
+        self.encode(&mut target);
+
+        target.freeze()
     }
 
-    pub async fn write_to<TTarget>(&self, target: &mut TTarget) -> Result<(), Error>
-    where
-        TTarget: AsyncWrite + Unpin,
-    {
-        target.write_u8(self.into()).await?;
+    /// Appends the type and the value in big-endian, blobs and strings prefixed by their length.
+    pub fn encode(&self, target: &mut BytesMut) {
+        target.put_u8(self.into());
 
         match self {
-            Value::Boolean(_) => panic!(),
-            Value::Blob(v) => {
-                target.write_u32::<BigEndian>(v.len() as u32).await?;
-                tokio::io::AsyncWriteExt::write_all(target, &v).await?;
-            },
-            Value::String(v) => {
-                target.write_u32::<BigEndian>(v.len() as u32).await?;
-                tokio::io::AsyncWriteExt::write_all(target, v.as_bytes()).await?;
-            },
-            Value::U8(v) => target.write_u8(*v).await?,
-            Value::I8(v) => target.write_i8(*v).await?,
+            Value::Boolean(v) => target.put_u8(*v as u8),
+            Value::Blob(v) => put_with_len(target, v),
+            Value::String(v) => put_with_len(target, v.as_bytes()),
 
-            Value::U16(v) => target.write_u16::<BigEndian>(*v).await?,
-            Value::I16(v) => target.write_i16::<BigEndian>(*v).await?,
+            Value::U8(v) => target.put_u8(*v),
+            Value::I8(v) => target.put_i8(*v),
 
-            Value::U32(v) => target.write_u32::<BigEndian>(*v).await?,
-            Value::I32(v) => target.write_i32::<BigEndian>(*v).await?,
+            Value::U16(v) => target.put_u16(*v),
+            Value::I16(v) => target.put_i16(*v),
 
-            Value::U64(v) => target.write_u64::<BigEndian>(*v).await?,
-            Value::I64(v) => target.write_i64::<BigEndian>(*v).await?,
+            Value::U32(v) => target.put_u32(*v),
+            Value::I32(v) => target.put_i32(*v),
 
-            Value::F32(v) => target.write_f32::<BigEndian>(*v).await?,
-            Value::F64(v) => target.write_f64::<BigEndian>(*v).await?,
+            Value::U64(v) => target.put_u64(*v),
+            Value::I64(v) => target.put_i64(*v),
+
+            Value::F32(v) => target.put_f32(*v),
+            Value::F64(v) => target.put_f64(*v),
         };
+    }
+
+    /// The number of bytes `encode` appends.
+    pub fn encoded_len(&self) -> usize {
+        1 + match self {
+            Value::Boolean(_) | Value::U8(_) | Value::I8(_) => 1,
+            Value::Blob(v) => 4 + v.len(),
+            Value::String(v) => 4 + v.len(),
+            Value::U16(_) | Value::I16(_) => 2,
+            Value::U32(_) | Value::I32(_) | Value::F32(_) => 4,
+            Value::U64(_) | Value::I64(_) | Value::F64(_) => 8,
+        }
+    }
 
-        Ok(())
+    pub async fn write_to<TTarget>(&self, target: &mut TTarget) -> Result<(), Error>
+    where
+        TTarget: AsyncWrite + Unpin,
+    {
+        tokio::io::AsyncWriteExt::write_all(target, &self.as_span()).await
     }
 
     pub async fn read_from<TSource>(source: &mut TSource) -> Result<Self, Error>
@@ -74,7 +89,7 @@ impl Value {
 
         let result = match value_type {
             // Boolean
-            1 => panic!(),
+            1 => Value::Boolean(source.read_u8().await? != 0),
             // Blob
             2 => {
                 let len = source.read_u32::<BigEndian>().await?;
@@ -117,6 +132,17 @@ impl Value {
     }
 }
 
+/// Encodes a string like `Value::String`, without owning it.
+pub(crate) fn encode_str(target: &mut BytesMut, string: &str) {
+    target.put_u8(3);
+    put_with_len(target, string.as_bytes());
+}
+
+fn put_with_len(target: &mut BytesMut, data: &[u8]) {
+    target.put_u32(data.len() as u32);
+    target.put_slice(data);
+}
+
 impl From<&Value> for u8 {
     fn from(value: &Value) -> Self {
         match value {
@@ -169,6 +195,17 @@ mod tests {
         assert_eq!(u8::from(Value::F64(0f64)), 13);
     }
 
+    #[tokio::test]
+    async fn serializes_boolean_correctly() {
+        let mut cursor = std::io::Cursor::new(vec![0u8; 100]);
+        let value = Value::Boolean(true);
+        value.write_to(&mut cursor).await.unwrap();
+        assert_eq!(&cursor.get_ref()[0..2], &[1, 1]);
+
+        cursor.set_position(0);
+        assert_eq!(Value::read_from(&mut cursor).await.unwrap(), value);
+    }
+
     #[tokio::test]
     async fn serializes_8_correctly() {
         let mut cursor = std::io::Cursor::new(vec![0u8; 100]);
@@ -0,0 +1,58 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use schema::{parse, Schema};
+
+const NAMESPACES: usize = 100;
+const LINES: usize = 10;
+const POINTS: usize = 100;
+
+/// 100 namespaces with 10 nested ones of 100 points each, 100k points in total.
+fn schema() -> Schema {
+    let mut source = String::new();
+
+    for namespace in 0..NAMESPACES {
+        source.push_str(&format!("plant{} {{\n", namespace));
+
+        for line in 0..LINES {
+            source.push_str(&format!("line{} {{\n", line));
+
+            for point in 0..POINTS {
+                source.push_str(&format!("- point{}: i32\n", point));
+            }
+
+            source.push_str("}\n");
+        }
+
+        source.push_str("}\n");
+    }
+
+    Schema::new(parse(&source).unwrap())
+}
+
+fn lookup(c: &mut Criterion) {
+    let schema = schema();
+    assert_eq!(schema.points().count(), NAMESPACES * LINES * POINTS);
+
+    let name = "plant50/line5/point50";
+
+    c.bench_function("get", |b| b.iter(|| schema.get(black_box(name))));
+
+    // What `get` replaces.
+    c.bench_function("find", |b| {
+        b.iter(|| schema.points().find(|p| p.full_name == black_box(name)))
+    });
+
+    c.bench_function("query line", |b| {
+        b.iter(|| schema.query(black_box("plant50/line5/*")))
+    });
+
+    c.bench_function("query namespace", |b| {
+        b.iter(|| schema.query(black_box("plant50/**")))
+    });
+
+    c.bench_function("query all", |b| {
+        b.iter(|| schema.query(black_box("**/point50")))
+    });
+}
+
+criterion_group!(benches, lookup);
+criterion_main!(benches);
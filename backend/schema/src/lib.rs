@@ -12,7 +12,7 @@ pub use parser::*;
 pub use query::*;
 pub use schema::*;
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum PointType {
     Boolean,
     Blob,
@@ -29,6 +29,51 @@ pub enum PointType {
     F64,
 }
 
+impl PointType {
+    /// Every type, booleans and narrow numbers first.
+    pub const ALL: [PointType; 13] = [
+        PointType::Boolean,
+        PointType::U8,
+        PointType::I8,
+        PointType::U16,
+        PointType::I16,
+        PointType::U32,
+        PointType::I32,
+        PointType::U64,
+        PointType::I64,
+        PointType::F32,
+        PointType::F64,
+        PointType::String,
+        PointType::Blob,
+    ];
+
+    /// The type-name as it is written in a schema.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PointType::Boolean => "boolean",
+            PointType::Blob => "blob",
+            PointType::String => "string",
+            PointType::U8 => "u8",
+            PointType::I8 => "i8",
+            PointType::U16 => "u16",
+            PointType::I16 => "i16",
+            PointType::U32 => "u32",
+            PointType::I32 => "i32",
+            PointType::U64 => "u64",
+            PointType::I64 => "i64",
+            PointType::F32 => "f32",
+            PointType::F64 => "f64",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        PointType::ALL
+            .iter()
+            .find(|t| t.as_str().eq_ignore_ascii_case(name))
+            .copied()
+    }
+}
+
 #[derive(Debug, Eq)]
 pub struct Point {
     pub types: HashSet<PointType>,
@@ -50,6 +50,17 @@ impl QuerySet {
         Ok(())
     }
 
+    /// Returns false if the pattern was not part of the set.
+    pub fn remove_point(&mut self, point: &str) -> Result<bool, globset::Error> {
+        if !self.points.remove(point) {
+            return Ok(false);
+        }
+
+        self.globset = build_glob_matcher(&self.points)?;
+
+        Ok(true)
+    }
+
     pub fn matches(&self, candidate: &str) -> bool {
         self.globset.is_match(candidate)
     }
@@ -68,7 +79,7 @@ fn build_glob_matcher(source: &HashSet<String>) -> Result<GlobSet, globset::Erro
         builder.add(matcher);
     }
 
-    Ok(builder.build()?)
+    builder.build()
 }
 
 #[cfg(test)]
@@ -91,4 +102,16 @@ pub mod tests {
         assert!(set.matches("some_other_namespace/specific_point"));
         assert!(!set.matches("some_other_namespace/a_point"));
     }
+
+    #[test]
+    pub fn removes_points() {
+        let mut set = QuerySet::single("some_namespace/*").unwrap();
+        set.insert_point("other_namespace/*").unwrap();
+
+        assert!(set.remove_point("some_namespace/*").unwrap());
+        assert!(!set.remove_point("some_namespace/*").unwrap());
+
+        assert!(!set.matches("some_namespace/a_point"));
+        assert!(set.matches("other_namespace/a_point"));
+    }
 }
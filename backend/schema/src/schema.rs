@@ -1,34 +1,114 @@
-use crate::Point;
+use std::collections::HashMap;
+
+use crate::{Point, QuerySet, NS_DIVIDER};
 
 use super::Namespace;
 
+/// The points of all namespaces, looked up by their full name. Queries only match the points
+/// below the namespaces their pattern starts with, e.g. those of `plant/line1` and its nested
+/// namespaces for `plant/line1/*`.
 #[derive(Debug)]
 pub struct Schema {
-    namespaces: Vec<Namespace>,
+    points: Vec<Point>,
+    by_name: HashMap<String, usize>,
+    tree: NamespaceNode,
+}
+
+/// A namespace with the points directly in it, by position in `Schema::points`.
+#[derive(Debug, Default)]
+struct NamespaceNode {
+    children: HashMap<String, NamespaceNode>,
+    points: Vec<usize>,
+}
+
+impl NamespaceNode {
+    fn collect(&self, found: &mut Vec<usize>) {
+        found.extend(&self.points);
+
+        for child in self.children.values() {
+            child.collect(found);
+        }
+    }
 }
 
 impl Schema {
     pub fn new(namespaces: Vec<Namespace>) -> Self {
-        Schema {
-            namespaces,
+        let mut schema = Schema::empty();
+
+        for namespace in namespaces {
+            let mut node = &mut schema.tree;
+
+            for segment in namespace.name.split(NS_DIVIDER) {
+                node = node.children.entry(String::from(segment)).or_default();
+            }
+
+            for point in namespace.points {
+                let idx = schema.points.len();
+
+                // Of points declared twice the first wins, as it did before names were indexed.
+                schema.by_name.entry(point.full_name.clone()).or_insert(idx);
+                node.points.push(idx);
+                schema.points.push(point);
+            }
         }
+
+        schema
     }
 
     pub fn empty() -> Self {
         Schema {
-            namespaces: vec![],
+            points: vec![],
+            by_name: HashMap::new(),
+            tree: NamespaceNode::default(),
         }
     }
 
     pub fn points(&self) -> impl Iterator<Item = &Point> {
-        self.namespaces.iter()
-            .flat_map(|f| &f.points)
+        self.points.iter()
+    }
+
+    /// The point with exactly this name, e.g. `plant/line1/speed`.
+    pub fn get(&self, full_name: &str) -> Option<&Point> {
+        self.by_name.get(full_name).map(|&idx| &self.points[idx])
+    }
+
+    /// The points matching the glob, like `QuerySet` matches them, in the order of `points`.
+    pub fn query(&self, pattern: &str) -> Result<Vec<&Point>, globset::Error> {
+        let query = QuerySet::single(pattern)?;
+        let segments: Vec<&str> = pattern.split(NS_DIVIDER).collect();
+        let mut node = &self.tree;
+
+        // The last segment names the point. Patterns are matched case-insensitively, while
+        // identifiers are always lowercase.
+        for segment in &segments[..segments.len() - 1] {
+            if segment.contains(|c| "*?[]{}\\".contains(c)) {
+                break;
+            }
+
+            match node.children.get(&segment.to_lowercase()) {
+                Some(child) => node = child,
+                None => return Ok(vec![]),
+            }
+        }
+
+        let mut candidates = vec![];
+        node.collect(&mut candidates);
+        candidates.sort_unstable();
+
+        let points = candidates
+            .into_iter()
+            .map(|idx| &self.points[idx])
+            .filter(|point| query.matches(&point.full_name))
+            .collect();
+
+        Ok(points)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::parse;
+    use super::Schema;
+    use crate::{parse, Namespace, Point, PointType};
 
     #[test]
     pub fn it_works() {
@@ -67,4 +147,88 @@ mod tests {
 
         assert_eq!(6, schema.len());
     }
+
+    #[test]
+    pub fn looks_up_points() {
+        let namespaces = parse(
+            "
+            plant {
+                - speed: i32
+
+                line1 {
+                    - speed: i32
+                    - temp: f32
+
+                    motor {
+                        - rpm: u32
+                    }
+                }
+
+                line2 {
+                    - speed: i32
+                }
+            }
+
+            office {
+                - temp: f32
+            }
+        ",
+        )
+        .unwrap();
+
+        let schema = Schema::new(namespaces);
+        let names = |pattern: &str| {
+            let mut names: Vec<_> = schema
+                .query(pattern)
+                .unwrap()
+                .into_iter()
+                .map(|p| p.full_name.as_str())
+                .collect();
+
+            names.sort_unstable();
+            names
+        };
+
+        assert_eq!(schema.get("plant/line1/temp").unwrap().name, "temp");
+        assert!(schema.get("plant/line1").is_none());
+
+        assert_eq!(
+            names("plant/line1/*"),
+            ["plant/line1/speed", "plant/line1/temp"]
+        );
+        assert_eq!(names("Plant/Line1/Speed"), ["plant/line1/speed"]);
+        assert_eq!(
+            names("plant/**/speed"),
+            ["plant/line1/speed", "plant/line2/speed", "plant/speed"]
+        );
+        assert_eq!(
+            names("plant/line?/speed"),
+            ["plant/line1/speed", "plant/line2/speed"]
+        );
+        assert_eq!(names("*/temp"), ["office/temp"]);
+        assert_eq!(names("plant/line1/motor/**"), ["plant/line1/motor/rpm"]);
+        assert_eq!(names("factory/**"), Vec::<&str>::new());
+        assert_eq!(names("**").len(), 6);
+    }
+
+    #[test]
+    pub fn keeps_first_of_duplicated_points() {
+        let namespace = |point_type| Namespace {
+            name: String::from("plant"),
+            points: vec![Point::new(
+                String::from("speed"),
+                String::from("plant"),
+                vec![point_type].into_iter().collect(),
+            )]
+            .into_iter()
+            .collect(),
+        };
+
+        let schema = Schema::new(vec![namespace(PointType::I32), namespace(PointType::F32)]);
+        let speed = schema.get("plant/speed").unwrap();
+
+        assert_eq!(2, schema.points.len());
+        assert!(speed.types.contains(&PointType::I32));
+        assert!(!speed.types.contains(&PointType::F32));
+    }
 }
@@ -0,0 +1,11 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Builds don't depend on a protoc installed on the host.
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+
+    // Clients generate their own stubs from the .proto.
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/rmber.proto"], &["proto"])?;
+
+    Ok(())
+}
@@ -0,0 +1,254 @@
+use schema::QuerySet;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::auth::Principal;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Register a subscription-pattern.
+    Subscribe,
+    /// Receive the value of a point.
+    Read,
+    /// Update the value of a point.
+    Write,
+    /// Contribute points to the schema.
+    Register,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct GrantsFile {
+    #[serde(default)]
+    subscribe: Vec<String>,
+    #[serde(default)]
+    read: Vec<String>,
+    #[serde(default)]
+    write: Vec<String>,
+    #[serde(default)]
+    register: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AclFile {
+    #[serde(default)]
+    principals: HashMap<String, GrantsFile>,
+}
+
+/// The namespace-globs a single principal has access to.
+#[derive(Debug)]
+pub struct Grants {
+    subscribe: QuerySet,
+    read: QuerySet,
+    write: QuerySet,
+    register: QuerySet,
+}
+
+impl Grants {
+    pub fn all() -> Self {
+        let all = || QuerySet::single("**").unwrap();
+
+        Grants {
+            subscribe: all(),
+            read: all(),
+            write: all(),
+            register: all(),
+        }
+    }
+
+    pub fn none() -> Self {
+        Grants {
+            subscribe: QuerySet::empty(),
+            read: QuerySet::empty(),
+            write: QuerySet::empty(),
+            register: QuerySet::empty(),
+        }
+    }
+
+    fn from_file(file: GrantsFile) -> Result<Self, globset::Error> {
+        Ok(Grants {
+            subscribe: QuerySet::new(file.subscribe)?,
+            read: QuerySet::new(file.read)?,
+            write: QuerySet::new(file.write)?,
+            register: QuerySet::new(file.register)?,
+        })
+    }
+
+    /// `key` is a point's full name, or the pattern itself for `Permission::Subscribe`.
+    pub fn allows(&self, permission: Permission, key: &str) -> bool {
+        match permission {
+            Permission::Subscribe => self.subscribe.matches(key),
+            Permission::Read => self.read.matches(key),
+            Permission::Write => self.write.matches(key),
+            Permission::Register => self.register.matches(key),
+        }
+    }
+}
+
+/// Per-principal grants read from a TOML file. Principals that are not listed get nothing:
+///
+/// ```toml
+/// [principals.dashboard]
+/// subscribe = ["plant/**"]
+/// read = ["plant/**"]
+///
+/// [principals.producer]
+/// write = ["plant/line1/**"]
+/// register = ["plant/line1/**"]
+/// ```
+#[derive(Debug)]
+pub struct Acl {
+    principals: HashMap<String, Arc<Grants>>,
+    none: Arc<Grants>,
+}
+
+impl Acl {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let source = std::fs::read_to_string(path)?;
+
+        Acl::parse(&source)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let file: AclFile = match toml::from_str(source) {
+            Ok(file) => file,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        };
+
+        let mut principals = HashMap::new();
+
+        for (name, grants) in file.principals {
+            let grants = match Grants::from_file(grants) {
+                Ok(g) => g,
+                Err(e) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Invalid glob for principal {}: {}", name, e),
+                    ))
+                }
+            };
+
+            principals.insert(name, Arc::new(grants));
+        }
+
+        Ok(Acl {
+            principals,
+            none: Arc::new(Grants::none()),
+        })
+    }
+
+    pub fn grants(&self, principal: &Principal) -> Arc<Grants> {
+        match self.principals.get(&principal.name) {
+            Some(grants) => grants.clone(),
+            None => self.none.clone(),
+        }
+    }
+}
+
+/// Without an acl every principal may do everything.
+pub fn grants_for(acl: &Option<Acl>, principal: &Principal) -> Arc<Grants> {
+    match acl {
+        Some(acl) => acl.grants(principal),
+        None => Arc::new(Grants::all()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{producer, request, secured, spawn_server, update, PCT};
+    use protocol::{StringKey, PACKET_PERMISSION_ERR};
+
+    fn acl() -> Acl {
+        Acl::parse(
+            "
+            [principals.dashboard]
+            subscribe = [\"plant/**\"]
+            read = [\"plant/**\"]
+
+            [principals.producer]
+            write = [\"plant/line1/*\"]
+            register = [\"plant/line1/*\"]
+            ",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn grants_are_per_principal() {
+        let acl = acl();
+
+        let dashboard = acl.grants(&Principal::new("dashboard"));
+        assert!(dashboard.allows(Permission::Read, "plant/line1/speed"));
+        assert!(dashboard.allows(Permission::Subscribe, "plant/line1/*"));
+        assert!(!dashboard.allows(Permission::Subscribe, "**"));
+        assert!(!dashboard.allows(Permission::Write, "plant/line1/speed"));
+
+        let producer = acl.grants(&Principal::new("producer"));
+        assert!(producer.allows(Permission::Write, "plant/line1/speed"));
+        assert!(!producer.allows(Permission::Write, "plant/line2/speed"));
+        assert!(!producer.allows(Permission::Read, "plant/line1/speed"));
+    }
+
+    #[test]
+    fn unknown_principals_get_nothing() {
+        let grants = acl().grants(&Principal::new("someone"));
+
+        assert!(!grants.allows(Permission::Subscribe, "plant/**"));
+        assert!(!grants.allows(Permission::Read, "plant/line1/speed"));
+        assert!(!grants.allows(Permission::Write, "plant/line1/speed"));
+        assert!(!grants.allows(Permission::Register, "plant/line1/speed"));
+    }
+
+    #[tokio::test]
+    async fn packets_are_checked_against_the_acl() {
+        let server = spawn_server(secured(vec![]));
+
+        let denied =
+            |packet| matches!(packet, PCT::Error { code, .. } if code == PACKET_PERMISSION_ERR);
+
+        let mut producer = producer(&server.handle).await;
+
+        let (mut dashboard, remote) = tokio::io::duplex(1024);
+        server.handle.connect(remote).unwrap();
+
+        let authenticate = PCT::Authenticate {
+            user: String::new(),
+            secret: String::from("dashboard-token"),
+        };
+        assert_eq!(request(&mut dashboard, authenticate).await, PCT::Ok {});
+
+        let schema = PCT::RegisterSchema {
+            schema: String::from("plant { - temp: i32 }"),
+        };
+        assert!(denied(request(&mut dashboard, schema).await));
+        assert!(denied(
+            request(&mut producer, update("office/temp", 20)).await
+        ));
+
+        let subscribe = |pattern| PCT::Subscribe {
+            id: StringKey::new(pattern).unwrap(),
+        };
+        assert!(denied(request(&mut dashboard, subscribe("**")).await));
+        assert_eq!(
+            request(&mut dashboard, subscribe("plant/**")).await,
+            PCT::Ok {}
+        );
+
+        // The dashboard may not read the second line, so it only gets the first.
+        assert_eq!(
+            request(&mut producer, update("plant/line2/speed", 1)).await,
+            PCT::Ok {}
+        );
+        assert_eq!(
+            request(&mut producer, update("plant/line1/speed", 2)).await,
+            PCT::Ok {}
+        );
+        assert_eq!(
+            PCT::read_from(&mut dashboard).await.unwrap(),
+            update("plant/line1/speed", 2)
+        );
+    }
+}
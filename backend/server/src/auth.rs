@@ -0,0 +1,348 @@
+use argon2::password_hash::{PasswordHasher, SaltString};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::Engine;
+use blake2::{Blake2s256, Digest};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How long a connection may stay unauthenticated before it is dropped, unless configured.
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a verified `Authorization` header is trusted without running argon2 again.
+pub const VERIFIED_TTL: Duration = Duration::from_secs(60);
+
+/// Headers kept by `VerifiedHeaders`, so clients sending ever new passwords can't grow it.
+const VERIFIED_CAPACITY: usize = 1024;
+
+/// The identity a connection has authenticated as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub name: String,
+}
+
+impl Principal {
+    pub fn new(name: &str) -> Self {
+        Principal {
+            name: String::from(name),
+        }
+    }
+
+    /// Used for every connection when the server runs without credentials.
+    pub fn anonymous() -> Self {
+        Principal::new("anonymous")
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CredentialsFile {
+    #[serde(default)]
+    users: Vec<User>,
+    #[serde(default)]
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    name: String,
+    /// Argon2 hash in PHC string format, e.g. `$argon2id$v=19$...`.
+    password: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Token {
+    principal: String,
+    token: String,
+}
+
+/// Users and bearer-tokens read from a TOML credentials file:
+///
+/// ```toml
+/// [[users]]
+/// name = "producer"
+/// password = "$argon2id$v=19$m=19456,t=2,p=1$..."
+///
+/// [[tokens]]
+/// principal = "dashboard"
+/// token = "some-long-random-string"
+/// ```
+#[derive(Debug)]
+pub struct Credentials {
+    users: Vec<User>,
+    tokens: Vec<Token>,
+}
+
+impl Credentials {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let source = std::fs::read_to_string(path)?;
+
+        Credentials::parse(&source)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let file: CredentialsFile = match toml::from_str(source) {
+            Ok(file) => file,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        };
+
+        // Reject broken hashes up-front instead of failing every login for that user.
+        for user in &file.users {
+            if let Err(e) = PasswordHash::new(&user.password) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid password-hash for user {}: {}", user.name, e),
+                ));
+            }
+        }
+
+        Ok(Credentials {
+            users: file.users,
+            tokens: file.tokens,
+        })
+    }
+
+    /// An empty `user` means `secret` is a bearer-token.
+    pub fn authenticate(&self, user: &str, secret: &str) -> Option<Principal> {
+        if user.is_empty() {
+            return self
+                .tokens
+                .iter()
+                .find(|t| constant_time_eq(t.token.as_bytes(), secret.as_bytes()))
+                .map(|t| Principal::new(&t.principal));
+        }
+
+        // Unknown users are verified as well, so they take as long to reject as a wrong password.
+        let entry = self.users.iter().find(|u| u.name.eq(user));
+        let hash = match entry {
+            Some(entry) => &entry.password,
+            None => dummy_hash(),
+        };
+        let hash = PasswordHash::new(hash).ok()?;
+        let verified = Argon2::default()
+            .verify_password(secret.as_bytes(), &hash)
+            .is_ok();
+
+        match entry {
+            Some(entry) if verified => Some(Principal::new(&entry.name)),
+            _ => None,
+        }
+    }
+
+    /// Authenticates an HTTP `Authorization` header, either `Bearer <token>` or
+    /// `Basic <base64(user:password)>`.
+    pub fn authenticate_header(&self, header: &str) -> Option<Principal> {
+        if let Some(token) = header.strip_prefix("Bearer ") {
+            return self.authenticate("", token.trim());
+        }
+
+        let encoded = header.strip_prefix("Basic ")?;
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+
+        if user.is_empty() {
+            return None;
+        }
+
+        self.authenticate(user, password)
+    }
+}
+
+/// Basic-auth headers that passed verification within `VERIFIED_TTL`. HTTP-clients send their
+/// password with every request, this spares them argon2 on all but the first. Only a digest of
+/// the header is kept, never the password itself.
+#[derive(Debug, Default)]
+pub struct VerifiedHeaders {
+    entries: Mutex<HashMap<[u8; 32], (Principal, Instant)>>,
+}
+
+impl VerifiedHeaders {
+    pub fn get(&self, header: &str) -> Option<Principal> {
+        let entries = self.entries.lock().unwrap();
+
+        match entries.get(&digest(header)) {
+            Some((principal, verified)) if verified.elapsed() < VERIFIED_TTL => {
+                Some(principal.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, header: &str, principal: Principal) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= VERIFIED_CAPACITY {
+            entries.retain(|_, (_, verified)| verified.elapsed() < VERIFIED_TTL);
+        }
+
+        if entries.len() >= VERIFIED_CAPACITY {
+            entries.clear();
+        }
+
+        entries.insert(digest(header), (principal, Instant::now()));
+    }
+}
+
+fn digest(header: &str) -> [u8; 32] {
+    Blake2s256::digest(header.as_bytes()).into()
+}
+
+/// Runs `verify` on tokio's blocking pool. Verifying a password is deliberately expensive, on a
+/// worker of the runtime it would hold up every client sharing that worker.
+pub async fn verify_blocking<F>(
+    credentials: &Arc<Option<Credentials>>,
+    verify: F,
+) -> Option<Principal>
+where
+    F: FnOnce(&Credentials) -> Option<Principal> + Send + 'static,
+{
+    let credentials = credentials.clone();
+
+    tokio::task::spawn_blocking(move || credentials.as_ref().as_ref().and_then(verify))
+        .await
+        .ok()
+        .flatten()
+}
+
+/// A hash of the empty password with the default parameters, computed once.
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+
+    DUMMY.get_or_init(|| {
+        let salt = SaltString::encode_b64(b"rmber-dummy-salt").unwrap();
+
+        Argon2::default()
+            .hash_password(b"", &salt)
+            .unwrap()
+            .to_string()
+    })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::{Algorithm, Params, Version};
+
+    fn hash(password: &str) -> String {
+        // Cheap parameters, the verifier reads them back from the PHC string.
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let salt = SaltString::encode_b64(b"rmber-test-salt").unwrap();
+
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
+    fn credentials() -> Credentials {
+        let source = format!(
+            "
+            [[users]]
+            name = \"producer\"
+            password = \"{}\"
+
+            [[tokens]]
+            principal = \"dashboard\"
+            token = \"secret-token\"
+            ",
+            hash("hunter2")
+        );
+
+        Credentials::parse(&source).unwrap()
+    }
+
+    #[test]
+    fn password_authentication() {
+        let credentials = credentials();
+
+        assert_eq!(
+            credentials.authenticate("producer", "hunter2"),
+            Some(Principal::new("producer"))
+        );
+        assert_eq!(credentials.authenticate("producer", "hunter3"), None);
+        assert_eq!(credentials.authenticate("someone", "hunter2"), None);
+        assert_eq!(credentials.authenticate("someone", ""), None);
+    }
+
+    #[tokio::test]
+    async fn verifies_on_the_blocking_pool() {
+        let credentials = Arc::new(Some(credentials()));
+
+        let principal =
+            verify_blocking(&credentials, |c| c.authenticate("producer", "hunter2")).await;
+        assert_eq!(principal, Some(Principal::new("producer")));
+
+        let principal = verify_blocking(&Arc::new(None), |c| c.authenticate("", "")).await;
+        assert_eq!(principal, None);
+    }
+
+    #[test]
+    fn token_authentication() {
+        let credentials = credentials();
+
+        assert_eq!(
+            credentials.authenticate("", "secret-token"),
+            Some(Principal::new("dashboard"))
+        );
+        assert_eq!(credentials.authenticate("", "secret-tokem"), None);
+        assert_eq!(credentials.authenticate("", ""), None);
+    }
+
+    #[test]
+    fn header_authentication() {
+        let credentials = credentials();
+
+        assert_eq!(
+            credentials.authenticate_header("Bearer secret-token"),
+            Some(Principal::new("dashboard"))
+        );
+        // producer:hunter2
+        assert_eq!(
+            credentials.authenticate_header("Basic cHJvZHVjZXI6aHVudGVyMg=="),
+            Some(Principal::new("producer"))
+        );
+        // :secret-token, tokens are only accepted as bearer.
+        assert_eq!(
+            credentials.authenticate_header("Basic OnNlY3JldC10b2tlbg=="),
+            None
+        );
+        assert_eq!(credentials.authenticate_header("secret-token"), None);
+    }
+
+    #[test]
+    fn remembers_verified_headers() {
+        let verified = VerifiedHeaders::default();
+        verified.insert("Basic cHJvZHVjZXI6aHVudGVyMg==", Principal::new("producer"));
+
+        assert_eq!(
+            verified.get("Basic cHJvZHVjZXI6aHVudGVyMg=="),
+            Some(Principal::new("producer"))
+        );
+        // producer:hunter3
+        assert_eq!(verified.get("Basic cHJvZHVjZXI6aHVudGVyMw=="), None);
+    }
+
+    #[test]
+    fn rejects_invalid_hashes() {
+        let source = "
+            [[users]]
+            name = \"producer\"
+            password = \"plaintext\"
+        ";
+
+        assert!(Credentials::parse(source).is_err());
+    }
+}
@@ -0,0 +1,491 @@
+use schema::QuerySet;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+use crate::auth::AUTH_TIMEOUT;
+use crate::logging::{LogFormat, DEFAULT_FILTER};
+use crate::server::SHUTDOWN_TIMEOUT;
+
+/// The configuration of the server-binary, read from a TOML file:
+///
+/// ```toml
+/// [store]
+/// path = "/var/lib/rmber/db"
+///
+/// [listen]
+/// native = ["127.0.0.1:8080", "[::1]:8080"]
+/// mqtt = ["127.0.0.1:1883"]
+///
+/// [exporter]
+/// points = "plant/**"
+///
+/// [security]
+/// credentials = "/etc/rmber/credentials.toml"
+/// tls_cert = "/etc/rmber/cert.pem"
+/// tls_key = "/etc/rmber/key.pem"
+///
+/// [limits]
+/// max_native_connections = 1000
+/// auth_timeout = 10
+/// shutdown_timeout = 30
+/// slow_consumer = "disconnect"
+/// flush_interval = 500
+/// unbatched = ["trader"]
+///
+/// [log]
+/// filter = "info,server=debug"
+/// format = "json"
+/// ```
+///
+/// Missing keys keep their defaults, which listen on the loopback-interface only. A front-end
+/// without addresses is disabled, MQTT and RESP have none unless they are configured.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub store: StoreConfig,
+    pub listen: ListenConfig,
+    pub exporter: ExporterConfig,
+    pub security: SecurityConfig,
+    pub limits: Limits,
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConfig {
+    pub path: PathBuf,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            path: PathBuf::from("./db"),
+        }
+    }
+}
+
+/// The addresses of every front-end, each may listen on several.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    pub native: Vec<SocketAddr>,
+    pub websocket: Vec<SocketAddr>,
+    /// REST, SSE, GraphQL and the server's own metrics.
+    pub http: Vec<SocketAddr>,
+    /// Off by default, as it takes the port of a broker on the same host.
+    pub mqtt: Vec<SocketAddr>,
+    /// Off by default, as it takes the port of a Redis on the same host.
+    pub resp: Vec<SocketAddr>,
+    pub grpc: Vec<SocketAddr>,
+    /// Only used when `exporter.points` is set.
+    pub exporter: Vec<SocketAddr>,
+    /// Paths of unix-sockets for native connections. Off by default, so the server doesn't leave
+    /// sockets in whatever directory it was started from.
+    pub unix: Vec<PathBuf>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        let local = |port| vec![SocketAddr::from(([127, 0, 0, 1], port))];
+
+        ListenConfig {
+            native: local(8080),
+            websocket: local(8081),
+            http: local(8082),
+            mqtt: vec![],
+            resp: vec![],
+            grpc: local(50051),
+            exporter: local(9464),
+            unix: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExporterConfig {
+    /// A glob over the points to export as Prometheus-gauges, the exporter is off without it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub points: Option<String>,
+}
+
+/// Authentication, access control and TLS are disabled unless their files are configured. A
+/// configured file has to exist, the server doesn't start without it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acl: Option<PathBuf>,
+    /// Enables TLS together with `tls_key`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_cert: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_key: Option<PathBuf>,
+    /// Client-certificates signed by this CA authenticate their connection.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_client_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Native connections, over TCP, websockets or unix-sockets, beyond this are closed right
+    /// away, unlimited if unset. Clients of the other front-ends aren't counted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_native_connections: Option<usize>,
+    /// How long a connection may stay unauthenticated before it is dropped, in seconds.
+    #[serde(with = "seconds")]
+    pub auth_timeout: Duration,
+    /// How long a shutdown may take to notify clients, drain updates and flush the store, in
+    /// seconds.
+    #[serde(with = "seconds")]
+    pub shutdown_timeout: Duration,
+    /// Errors and disconnects of connections that wait for the event-loop. A connection stops
+    /// reading while the queue is full.
+    pub packet_queue: usize,
+    /// Updates that wait to be sent to a connection, or to a subscription of another front-end.
+    pub outbound_queue: usize,
+    /// Applies to connections whose outbound queue is full, subscriptions of other front-ends
+    /// drop the newest update.
+    pub slow_consumer: SlowConsumerPolicy,
+    /// Bytes of updates a connection writes at once, a full batch is written right away.
+    pub batch_size: usize,
+    /// How long a connection waits for more updates to fill a batch, in microseconds. Rounded up
+    /// to the timer's millisecond. Off by default, 0 writes every update on its own without
+    /// delaying it.
+    #[serde(with = "micros")]
+    pub flush_interval: Duration,
+    /// Principals whose updates are written on their own when `flush_interval` is set, e.g.
+    /// latency-sensitive clients.
+    pub unbatched: Vec<String>,
+    /// Bytes a RESP-client may send for a single command, which is buffered until it is complete.
+    pub max_command_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_native_connections: None,
+            auth_timeout: AUTH_TIMEOUT,
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+            packet_queue: 1024,
+            outbound_queue: 1024,
+            slow_consumer: SlowConsumerPolicy::Coalesce,
+            batch_size: 16 * 1024,
+            flush_interval: Duration::ZERO,
+            unbatched: vec![],
+            max_command_size: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// What happens to a connection that reads slower than updates arrive, once its outbound queue
+/// is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// The connection is closed, the client has to reconnect.
+    Disconnect,
+    /// The oldest queued update makes room for the new one.
+    DropOldest,
+    /// An unsent update of the same point takes the new value and keeps its place, so the client
+    /// only misses intermediate values. Otherwise the oldest queued update makes room.
+    Coalesce,
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            "drop_oldest" => Ok(SlowConsumerPolicy::DropOldest),
+            "coalesce" => Ok(SlowConsumerPolicy::Coalesce),
+            _ => Err(invalid(format!(
+                "Unknown slow-consumer policy {}, expected disconnect, drop_oldest or coalesce.",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// In the syntax of `RUST_LOG`, which overrides it.
+    pub filter: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            filter: String::from(DEFAULT_FILTER),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let source = std::fs::read_to_string(path)?;
+
+        Config::parse(&source)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        toml::from_str(source).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// The configuration as TOML, including every default.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("Config only consists of tables of values.")
+    }
+
+    /// The exporter is only enabled with a glob over the points it exports.
+    pub fn exporter_addresses(&self) -> &[SocketAddr] {
+        match self.exporter.points {
+            Some(_) => &self.listen.exporter,
+            None => &[],
+        }
+    }
+
+    /// Catches what would otherwise only fail after some of the listeners are bound.
+    pub fn validate(&self) -> Result<(), Error> {
+        let listen = &self.listen;
+        let addresses = [
+            &listen.native,
+            &listen.websocket,
+            &listen.http,
+            &listen.mqtt,
+            &listen.resp,
+            &listen.grpc,
+        ];
+
+        let mut used = BTreeSet::new();
+
+        for address in addresses
+            .iter()
+            .copied()
+            .flatten()
+            .chain(self.exporter_addresses())
+        {
+            if !used.insert(address) {
+                return Err(invalid(format!(
+                    "Address {} is used by more than one listener.",
+                    address
+                )));
+            }
+        }
+
+        if used.is_empty() && listen.unix.is_empty() {
+            return Err(invalid(String::from("No front-end is enabled.")));
+        }
+
+        if cfg!(not(unix)) && !listen.unix.is_empty() {
+            return Err(invalid(String::from(
+                "Unix-sockets are not supported on this platform.",
+            )));
+        }
+
+        let security = &self.security;
+
+        if security.tls_cert.is_some() != security.tls_key.is_some() {
+            return Err(invalid(String::from(
+                "tls_cert and tls_key must be configured together.",
+            )));
+        }
+
+        if security.tls_client_ca.is_some() && security.tls_cert.is_none() {
+            return Err(invalid(String::from(
+                "tls_client_ca needs tls_cert and tls_key.",
+            )));
+        }
+
+        if let Some(points) = &self.exporter.points {
+            if let Err(e) = QuerySet::single(points) {
+                return Err(invalid(format!("Invalid glob for exported points: {}", e)));
+            }
+        }
+
+        if self.limits.max_native_connections == Some(0) {
+            return Err(invalid(String::from(
+                "max_native_connections must not be 0.",
+            )));
+        }
+
+        if self.limits.auth_timeout.is_zero() {
+            return Err(invalid(String::from("auth_timeout must not be 0.")));
+        }
+
+        if self.limits.packet_queue == 0 || self.limits.outbound_queue == 0 {
+            return Err(invalid(String::from("Queues must hold at least one entry.")));
+        }
+
+        if self.limits.batch_size == 0 {
+            return Err(invalid(String::from("batch_size must not be 0.")));
+        }
+
+        if self.limits.max_command_size == 0 {
+            return Err(invalid(String::from("max_command_size must not be 0.")));
+        }
+
+        if self.limits.shutdown_timeout.is_zero() {
+            return Err(invalid(String::from("shutdown_timeout must not be 0.")));
+        }
+
+        if let Err(e) = EnvFilter::try_new(&self.log.filter) {
+            return Err(invalid(format!("Invalid log-filter: {}", e)));
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+/// Durations are written as whole seconds.
+mod seconds {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_secs)
+    }
+}
+
+/// Durations are written as whole microseconds.
+mod micros {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_micros() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_micros)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_keys_keep_defaults() {
+        let config = Config::parse(
+            r#"
+            [listen]
+            native = ["0.0.0.0:9000", "[::1]:9000"]
+            mqtt = ["127.0.0.1:1883"]
+
+            [limits]
+            auth_timeout = 3
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.listen.native,
+            vec![
+                "0.0.0.0:9000".parse::<SocketAddr>().unwrap(),
+                "[::1]:9000".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            config.listen.mqtt,
+            vec!["127.0.0.1:1883".parse::<SocketAddr>().unwrap()]
+        );
+        assert!(config.listen.resp.is_empty());
+        assert_eq!(config.listen.http, ListenConfig::default().http);
+        assert_eq!(config.limits.auth_timeout, Duration::from_secs(3));
+        assert_eq!(config.limits.flush_interval, Duration::ZERO);
+        assert_eq!(config.store, StoreConfig::default());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(Config::parse("[listen]\nnatve = []").is_err());
+        assert!(Config::parse("[store]\npath = 1").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        let mut config = Config::default();
+        config.listen.mqtt = config.listen.native.clone();
+        assert!(config.validate().is_err());
+
+        // The exporter's default address only counts once it is enabled.
+        let mut config = Config::default();
+        config.listen.grpc = config.listen.exporter.clone();
+        assert!(config.validate().is_ok());
+        config.exporter.points = Some(String::from("plant/**"));
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.exporter.points = Some(String::from("plant/[a"));
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.limits.max_native_connections = Some(0);
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.log.filter = String::from("server=loud");
+        assert!(config.validate().is_err());
+
+        let config = Config::parse(
+            "[listen]\nnative = []\nwebsocket = []\nhttp = []\nmqtt = []\nresp = []\ngrpc = []\nunix = []",
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn printed_config_parses_again() {
+        let mut config = Config::default();
+        config.listen.native.push("[::]:8080".parse().unwrap());
+        config.exporter.points = Some(String::from("plant/**"));
+        config.limits.max_native_connections = Some(10);
+        config.limits.slow_consumer = SlowConsumerPolicy::DropOldest;
+        config.limits.flush_interval = Duration::from_micros(250);
+        config.limits.unbatched.push(String::from("trader"));
+        config.log.format = LogFormat::Json;
+        config.security.credentials = Some(PathBuf::from("/etc/rmber/credentials.toml"));
+
+        assert_eq!(Config::parse(&config.to_toml()).unwrap(), config);
+    }
+
+    #[test]
+    fn security_is_off_unless_configured() {
+        assert_eq!(Config::default().security, SecurityConfig::default());
+        assert!(Config::default().security.credentials.is_none());
+
+        let mut config = Config::default();
+        config.security.tls_cert = Some(PathBuf::from("cert.pem"));
+        assert!(config.validate().is_err());
+
+        config.security.tls_key = Some(PathBuf::from("key.pem"));
+        assert!(config.validate().is_ok());
+
+        let mut config = Config::default();
+        config.security.tls_client_ca = Some(PathBuf::from("client-ca.pem"));
+        assert!(config.validate().is_err());
+    }
+}
@@ -1,96 +1,214 @@
+use bytes::{Bytes, BytesMut};
+use metrics::{counter, histogram};
 use protocol::{Packet, StringKey};
 use schema::QuerySet;
-use std::cell::RefCell;
+use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::{
-    net::TcpStream,
-    sync::{mpsc::UnboundedSender, Mutex},
-};
+use std::path::PathBuf;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::task::JoinHandle;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, field, info_span, warn, Instrument, Span};
+
+use crate::acl::{Grants, Permission};
+use crate::auth::Principal;
+use crate::config::Limits;
+use crate::event_handlers::handle_packet;
+use crate::outbound::{Delivery, Hold, Outbound, Queued};
+use crate::server::State;
 
 pub type ConnectionId = protocol::RawKey<8>;
 
-#[derive(Debug)]
+pub type ReadStream = Box<dyn AsyncRead + Send + Unpin>;
+pub type WriteStream = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Where a connection came from, only used for logging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    /// Peers of a unix-socket are usually unnamed, so this is the socket the server listens on.
+    Unix(PathBuf),
+    /// Attached through `ServerHandle`.
+    InProcess,
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Tcp(address) => write!(f, "{}", address),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+            Address::InProcess => write!(f, "in-process"),
+        }
+    }
+}
+
+/// An accepted stream that is ready to be registered with the server.
+pub struct Incoming {
+    pub read: ReadStream,
+    pub write: WriteStream,
+    pub address: Address,
+    /// Set when the transport already identified the peer, e.g. by a client-certificate.
+    pub principal: Option<Principal>,
+    /// Set for websockets, whose clients expect every packet in a frame of its own.
+    pub framed: bool,
+}
+
+impl std::fmt::Debug for Incoming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Incoming")
+            .field("address", &self.address)
+            .field("principal", &self.principal)
+            .field("framed", &self.framed)
+            .finish()
+    }
+}
+
 pub struct Connection {
     pub id: ConnectionId,
-    pub address: SocketAddr,
+    pub address: Address,
 
-    read: Arc<Mutex<OwnedReadHalf>>,
-    write: Arc<Mutex<OwnedWriteHalf>>,
-    subscriptions: RefCell<QuerySet>,
-    raw_schema: RefCell<Option<String>>,
+    read: Arc<AsyncMutex<ReadStream>>,
+    write: Arc<AsyncMutex<WriteStream>>,
+    subscriptions: RwLock<QuerySet>,
+    raw_schema: Mutex<Option<String>>,
+    principal: Mutex<Option<Principal>>,
+    grants: RwLock<Arc<Grants>>,
+    reader: Mutex<Option<JoinHandle<()>>>,
+    outbound: Arc<Outbound>,
+    batching: Arc<Batching>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    /// Carries the id, peer and principal into everything logged for this connection.
+    span: Span,
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream, address: SocketAddr) -> Result<Self, Error> {
-        let (read, write) = stream.into_split();
-
+    pub fn new(
+        read: ReadStream,
+        write: WriteStream,
+        address: Address,
+        limits: &Limits,
+    ) -> Result<Self, Error> {
         match ConnectionId::new_random() {
             Ok(id) => Ok(Connection {
                 id,
-                read: Arc::new(Mutex::new(read)),
-                write: Arc::new(Mutex::new(write)),
-                // write: RefCell::new(write),
+                span: info_span!(
+                    "connection",
+                    id = %id,
+                    peer = %address,
+                    principal = field::Empty
+                ),
+                read: Arc::new(AsyncMutex::new(read)),
+                write: Arc::new(AsyncMutex::new(write)),
                 address,
-                subscriptions: RefCell::new(QuerySet::empty()),
-                raw_schema: RefCell::new(None),
+                subscriptions: RwLock::new(QuerySet::empty()),
+                raw_schema: Mutex::new(None),
+                principal: Mutex::new(None),
+                grants: RwLock::new(Arc::new(Grants::none())),
+                reader: Mutex::new(None),
+                outbound: Arc::new(Outbound::new(limits.outbound_queue, limits.slow_consumer)),
+                batching: Arc::new(Batching::new(limits)),
+                writer: Mutex::new(None),
             }),
             Err(e) => Err(e),
         }
     }
 
-    pub fn listen(&self, tx: UnboundedSender<(ConnectionId, Result<Packet<StringKey>, Error>)>) {
+    /// Starts the read-loop, which handles each packet before it reads the next one, and the
+    /// write-loop, which sends the queued updates. Errors are handed to the event-loop.
+    pub fn listen(&self, state: Arc<State>) {
         let stream = self.read.clone();
-        let id = self.id.clone();
+        let id = self.id;
+        let span = self.span.clone();
 
-        tokio::spawn(async move {
+        let reader = tokio::spawn(async move {
             let mut stream = stream.lock().await;
 
             loop {
-                let packet = Packet::<StringKey>::read_from(&mut *stream).await;
-                let mut error_kind = None;
+                let e = match Packet::<StringKey>::read_from(&mut *stream).await {
+                    // The client gets its answers in the order it sent the packets.
+                    Ok(packet) => {
+                        handle_packet(&state, (id, packet)).await;
+                        continue;
+                    }
+                    Err(e) => e,
+                };
 
-                if let Err(e) = &packet {
-                    error_kind = Some(e.kind());
-                }
+                let error_kind = e.kind();
 
-                match tx.send((id, packet)) {
-                    Ok(_) => {}
-                    Err(_) => {
-                        println!("Exiting read-loop");
-                        break;
-                    },
+                // Waits while the event-loop is behind, so the client is slowed down as well.
+                if state.errors.send((id, e)).await.is_err() {
+                    debug!("Server stopped, exiting read-loop");
+                    break;
                 }
 
-                match error_kind {
-                    Some(
-                        ErrorKind::ConnectionReset
+                if matches!(
+                    error_kind,
+                    ErrorKind::ConnectionReset
                         | ErrorKind::ConnectionAborted
                         | ErrorKind::ConnectionRefused
                         | ErrorKind::NotConnected
-                        | ErrorKind::UnexpectedEof,
-                    ) => {
-                        println!("Exiting read-loop asd");
-                        break;
-                    }
-                    _ => {}
+                        | ErrorKind::UnexpectedEof
+                ) {
+                    debug!("Peer disconnected, exiting read-loop");
+                    break;
                 }
             }
+        }.instrument(span));
+
+        *self.reader.lock().unwrap() = Some(reader);
+
+        let outbound = self.outbound.clone();
+        let stream = self.write.clone();
+        let batching = self.batching.clone();
+        let writer =
+            tokio::spawn(write_updates(outbound, stream, batching).instrument(self.span.clone()));
 
-        });
+        *self.writer.lock().unwrap() = Some(writer);
+    }
+
+    /// Queues an encoded update, see `Outbound::push`.
+    pub fn push_update(&self, key: &Bytes, packet: &Bytes, since: Instant) -> Delivery {
+        self.outbound.push(key, packet, since)
+    }
 
+    /// Updates are queued but not sent until the guard is dropped, see `Outbound::hold`.
+    pub fn hold_updates(&self) -> Hold<'_> {
+        self.outbound.hold()
+    }
+
+    /// Sends the queued updates and closes the write-half, e.g. when the server shuts down.
+    pub fn finish(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.outbound.close();
+
+        let writer = self.writer.lock().unwrap().take();
+        let stream = self.write.clone();
+
+        async move {
+            if let Some(writer) = writer {
+                let _ = writer.await;
+            }
+
+            let _ = stream.lock().await.shutdown().await;
+        }
     }
 
     pub async fn write_packet(&self, packet: Packet<StringKey>) -> Result<(), Error> {
         let mut stream = self.write.lock().await;
 
         packet.write_to(&mut *stream).await?;
+        stream.flush().await?;
 
         Ok(())
     }
 
-    pub fn writer(&self) -> Arc<Mutex<OwnedWriteHalf>> {
+    pub fn writer(&self) -> Arc<AsyncMutex<WriteStream>> {
         self.write.clone()
     }
 
@@ -99,16 +217,13 @@ impl Connection {
 
         match self.write_packet(packet).await {
             Ok(_) => {}
-            Err(e) => {
-                println!(
-                    "Could not send OK-packet to connection {}. Reason: {:?}",
-                    self.id, e
-                );
-            }
+            Err(e) => warn!(error = %e, "Could not send OK-packet"),
         };
     }
 
     pub async fn send_err(&self, code: u32, error: &str) {
+        debug!(code, message = error, "Rejected packet");
+
         let packet = Packet::Error {
             code,
             message: String::from(error),
@@ -116,24 +231,279 @@ impl Connection {
 
         match self.write_packet(packet).await {
             Ok(_) => {}
-            Err(e) => {
-                println!(
-                    "Could not send ERR-packet to connection {}. Reason: {:?}",
-                    self.id, e
-                );
-            }
+            Err(e) => warn!(error = %e, "Could not send ERR-packet"),
         };
     }
 
-    pub fn subscription_set(&self) -> std::cell::RefMut<QuerySet> {
-        self.subscriptions.borrow_mut()
+    pub fn subscription_set(&self) -> RwLockWriteGuard<'_, QuerySet> {
+        self.subscriptions.write().unwrap()
     }
 
     pub fn set_schema(&self, new_schema: String) {
-        self.raw_schema.replace(Some(new_schema));
+        *self.raw_schema.lock().unwrap() = Some(new_schema);
+    }
+
+    pub fn get_schema(&self) -> Option<String> {
+        self.raw_schema.lock().unwrap().clone()
+    }
+
+    pub fn authenticate(&self, principal: Principal, grants: Arc<Grants>) {
+        self.span.record("principal", principal.name.as_str());
+        *self.principal.lock().unwrap() = Some(principal);
+        *self.grants.write().unwrap() = grants;
+    }
+
+    /// Writes every update on its own from now on, see `Limits::unbatched`.
+    pub fn disable_batching(&self) {
+        self.batching.enabled.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.principal.lock().unwrap().is_some()
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn allows(&self, permission: Permission, key: &str) -> bool {
+        self.grants.read().unwrap().allows(permission, key)
+    }
+
+    /// Whether an update of the point should be sent to this connection.
+    pub fn wants(&self, id: &str) -> bool {
+        wants(
+            &self.subscriptions.read().unwrap(),
+            &self.grants.read().unwrap(),
+            id,
+        )
     }
+}
+
+/// A subscriber gets an update when one of its patterns matches and it may read the point.
+pub fn wants(subscriptions: &QuerySet, grants: &Grants, id: &str) -> bool {
+    subscriptions.matches(id) && grants.allows(Permission::Read, id)
+}
+
+impl std::fmt::Debug for Connection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connection")
+            .field("id", &self.id)
+            .field("address", &self.address)
+            .field("principal", &self.principal)
+            .finish()
+    }
+}
+
+/// The connections in the order they were accepted, which is also the order their schemas are
+/// merged in, with a lookup by id. A connection may outlive its removal while one of its packets
+/// is still handled.
+#[derive(Debug, Default)]
+pub struct Connections {
+    list: Vec<Arc<Connection>>,
+    positions: HashMap<ConnectionId, usize>,
+}
+
+impl Connections {
+    pub fn push(&mut self, connection: Arc<Connection>) {
+        self.positions.insert(connection.id, self.list.len());
+        self.list.push(connection);
+    }
+
+    pub fn get(&self, id: &ConnectionId) -> Option<&Arc<Connection>> {
+        self.positions.get(id).map(|&idx| &self.list[idx])
+    }
+
+    /// Linear like `Vec::remove`, as the others keep their order.
+    pub fn remove(&mut self, id: &ConnectionId) -> Option<Arc<Connection>> {
+        let idx = self.positions.remove(id)?;
+        let connection = self.list.remove(idx);
+
+        for moved in &self.list[idx..] {
+            self.positions
+                .insert(moved.id, self.positions[&moved.id] - 1);
+        }
+
+        Some(connection)
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+        self.positions.clear();
+    }
+}
+
+impl Deref for Connections {
+    type Target = [Arc<Connection>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.list
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Otherwise the read-loop keeps the socket open until the peer hangs up.
+        if let Some(reader) = self.reader.get_mut().unwrap().take() {
+            reader.abort();
+        }
+
+        if let Some(writer) = self.writer.get_mut().unwrap().take() {
+            writer.abort();
+        }
+    }
+}
+
+/// How the write-loop packs updates into one write, see `Limits::flush_interval`.
+#[derive(Debug)]
+struct Batching {
+    size: usize,
+    interval: Duration,
+    enabled: AtomicBool,
+}
+
+impl Batching {
+    fn new(limits: &Limits) -> Self {
+        Batching {
+            size: limits.batch_size,
+            interval: limits.flush_interval,
+            enabled: AtomicBool::new(!limits.flush_interval.is_zero()),
+        }
+    }
+}
+
+async fn write_updates(
+    outbound: Arc<Outbound>,
+    stream: Arc<AsyncMutex<WriteStream>>,
+    batching: Arc<Batching>,
+) {
+    while let Some(Queued { packet, since, .. }) = outbound.next().await {
+        let mut started = vec![since];
+
+        let packet = if batching.enabled.load(Ordering::Relaxed) {
+            let mut batch = BytesMut::from(&packet[..]);
+            fill(&outbound, &batching, &mut batch, &mut started).await;
+
+            batch.freeze()
+        } else {
+            packet
+        };
+
+        let mut stream = stream.lock().await;
+
+        let written = match stream.write_all(&packet).await {
+            Ok(_) => stream.flush().await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = written {
+            debug!(error = %e, "Could not write update, exiting write-loop");
+            counter!("rmber_dropped_updates_total", "reason" => "write_failed")
+                .increment(started.len() as u64);
+            outbound.close();
+            break;
+        }
+
+        for since in started {
+            histogram!("rmber_fanout_seconds").record(since.elapsed());
+        }
+    }
+}
+
+/// Adds the updates queued within the flush-interval to the batch, until it is full.
+async fn fill(
+    outbound: &Outbound,
+    batching: &Batching,
+    batch: &mut BytesMut,
+    started: &mut Vec<Instant>,
+) {
+    let deadline = Instant::now() + batching.interval;
+
+    while batch.len() < batching.size {
+        match timeout_at(deadline, outbound.next()).await {
+            Ok(Some(Queued { packet, since, .. })) => {
+                batch.extend_from_slice(&packet);
+                started.push(since);
+            }
+            // Closed or timed out, what is batched so far is written.
+            Ok(None) | Err(_) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SlowConsumerPolicy;
+    use protocol::{encode_update, Value};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Keeps every write on its own.
+    #[derive(Clone, Default)]
+    struct Writes(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl AsyncWrite for Writes {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize, Error>> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn write(limits: &Limits, updates: i32) -> Vec<Vec<u8>> {
+        let outbound = Arc::new(Outbound::new(16, SlowConsumerPolicy::DropOldest));
+        let writes = Writes::default();
+        let stream: WriteStream = Box::new(writes.clone());
+        let key = StringKey::new("plant/speed").unwrap();
+
+        for i in 0..updates {
+            let packet = encode_update(&key, &Value::I32(i));
+            outbound.push(&Bytes::from_static(b"plant/speed"), &packet, Instant::now());
+        }
+
+        outbound.close();
+        write_updates(
+            outbound,
+            Arc::new(AsyncMutex::new(stream)),
+            Arc::new(Batching::new(limits)),
+        )
+        .await;
+
+        let writes = writes.0.lock().unwrap().clone();
+        writes
+    }
+
+    #[tokio::test]
+    async fn updates_are_written_in_batches() {
+        let update = encode_update(&StringKey::new("plant/speed").unwrap(), &Value::I32(0));
+
+        let mut limits = Limits {
+            flush_interval: Duration::from_millis(1),
+            ..Limits::default()
+        };
+        let writes = write(&limits, 5).await;
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[0].len(), 5 * update.len());
+
+        // A batch is written once it holds at least two updates.
+        limits.batch_size = update.len() + 1;
+        assert_eq!(write(&limits, 5).await.len(), 3);
 
-    pub fn get_schema(&self) -> std::cell::Ref<Option<String>> {
-        self.raw_schema.borrow()
+        limits.flush_interval = Duration::ZERO;
+        let writes = write(&limits, 5).await;
+        assert_eq!(writes.len(), 5);
+        assert_eq!(writes[0], update);
     }
 }
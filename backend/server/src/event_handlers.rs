@@ -1,142 +1,651 @@
+use bytes::Bytes;
+use metrics::{counter, gauge, histogram};
+use protocol::Value;
 use std::io::{Error, ErrorKind};
+use std::sync::{Arc, RwLock};
+use tokio::time::Instant;
+use tracing::{debug, debug_span, error, field, info, warn, Instrument};
 
-use protocol::{PACKET_SCHEMA_ERR, PACKET_SUBSCRIPTION_ERR, PACKET_UPDATE_ERR, Packet, StringKey};
+use protocol::{
+    PACKET_AUTH_ERR, PACKET_PERMISSION_ERR, PACKET_SCHEMA_ERR, PACKET_SUBSCRIPTION_ERR,
+    PACKET_UNAUTHENTICATED_ERR, PACKET_UPDATE_ERR, Packet, StringKey, encode_update, Key,
+};
+use schema::{parse, PointType, QuerySet};
 
 use crate::{
-    connection::Connection,
+    acl::{grants_for, Permission},
+    auth::{verify_blocking, Principal},
+    connection::{Connection, ConnectionId, Connections},
+    gateway::{
+        Action, EventStream, Input, PointInfo, PointValue, Rejection, Reply, Request,
+    },
+    outbound::Delivery,
     server::{
-        ConnectionErrorEvent, ConnectionEvent, EventContext, PacketEvent, PointUpdateEvent,
-        ServerErrorEvent,
+        ConnectionErrorEvent, ConnectionEvent, ErrorTx, EventStreams, GatewaySchemas,
+        PacketEvent, PointUpdateEvent, RequestEvent, RocksDBStore, ServerErrorEvent, State,
     },
+    subscriptions::{Subscriber, SubscriptionIndex},
+    values,
 };
 
-pub fn handle_new_connection((_, connections, tx, _): EventContext, connection: ConnectionEvent) {
-    let address = match connection.peer_addr() {
-        Ok(addr) => addr,
-        Err(_) => return,
-    };
+pub fn handle_new_connection(state: &Arc<State>, incoming: ConnectionEvent) {
+    let limits = &state.limits;
 
-    let connection = match Connection::new(connection, address) {
+    if let Some(max) = limits.max_native_connections {
+        if state.connections.read().unwrap().len() >= max {
+            // Dropping the streams closes the connection.
+            warn!(peer = %incoming.address, "Too many connections, closing new one");
+            return;
+        }
+    }
+
+    let connection = match Connection::new(incoming.read, incoming.write, incoming.address, limits)
+    {
         Ok(conn) => conn,
         Err(e) => {
-            println!("{:?}", e);
+            error!(error = %e, "Could not create connection");
             return;
         }
     };
 
-    connection.listen(tx.clone());
-    println!("New connection {}", &connection.id);
+    let connection = Arc::new(connection);
+    let _entered = connection.span().clone().entered();
 
-    connections.push(connection);
-}
+    // A batch would arrive as one frame.
+    if incoming.framed {
+        connection.disable_batching();
+    }
 
-pub async fn handle_packet(
-    (store, connections, packet_tx, point_tx): EventContext<'_>,
-    (id, packet): PacketEvent,
-) {
-    let connection = connections.iter().find(|c| c.id.eq(&id));
-    let connection = match connection {
-        Some(c) => c,
-        None => return,
+    info!("New connection");
+
+    // A principal from the transport, e.g. a client-certificate, skips the handshake.
+    let principal = match (incoming.principal, &*state.credentials) {
+        (Some(principal), _) => Some(principal),
+        (None, None) => Some(Principal::anonymous()),
+        (None, Some(_)) => None,
     };
 
-    match packet {
-        Packet::Subscribe { id } => {
-            let mut set = connection.subscription_set();
+    if let Some(principal) = principal {
+        info!(principal = %principal.name, "Authenticated");
 
-            match set.insert_point(id.as_str()) {
-                Ok(_) => connection.send_ok().await,
-                Err(e) => {
-                    connection
-                        .send_err(PACKET_SUBSCRIPTION_ERR, &e.to_string())
-                        .await
-                }
-            };
+        if limits.unbatched.contains(&principal.name) {
+            connection.disable_batching();
         }
-        Packet::RegisterSchema { schema } => {
-            connection.set_schema(schema);
 
-            let schemas = connections
-                .iter()
-                .map(|c| c.get_schema())
-                .filter(|c| c.is_some())
-                .map(|c| c.as_ref().unwrap().clone());
-
-            if let Err(e) = store.build_schema(schemas) {
-                connection.send_err(PACKET_SCHEMA_ERR, &e.to_string()).await;
-            } else {
-                connection.send_ok().await;
-            }
-        }
-        Packet::Update { id, new_value } => match store.update_point(&id, new_value).await {
-            Ok(value) => {
-                connection.send_ok().await;
-                point_tx.send((id, value)).unwrap();
-            }
-            Err(e) => connection.send_err(PACKET_UPDATE_ERR, &e.to_string()).await,
-        },
-        Packet::Error {
-            code: _,
-            message: _,
-        } => {
-            // In this case we emit a disconnect.
+        let grants = grants_for(&state.acl, &principal);
+        connection.authenticate(principal, grants);
+    } else {
+        let tx = state.errors.clone();
+        let id = connection.id;
+        let timeout = limits.auth_timeout;
+
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+
+            // Ignored by connection_error if the handshake completed in time.
             let msg = (
                 id,
-                Err(Error::new(ErrorKind::ConnectionAborted, "Client error.")),
+                Error::new(ErrorKind::TimedOut, "Authentication timed out."),
             );
 
-            packet_tx.send(msg).unwrap();
+            let _ = tx.send(msg).await;
+        });
+    }
+
+    // Registered before the first packet is read, which looks the connection up.
+    let count = {
+        let mut connections = state.connections.write().unwrap();
+        connections.push(connection.clone());
+        connections.len()
+    };
+
+    connection.listen(state.clone());
+    gauge!("rmber_connections").set(count as f64);
+}
+
+pub async fn handle_packet(state: &State, (id, packet): PacketEvent) {
+    counter!("rmber_packets_total", "type" => packet.name()).increment(1);
+
+    let connection = match state.connections.read().unwrap().get(&id) {
+        Some(c) => c.clone(),
+        None => return,
+    };
+
+    let State {
+        store,
+        connections,
+        errors,
+        credentials,
+        acl,
+        schemas,
+        limits,
+        subscriptions,
+        ..
+    } = state;
+
+    let span = debug_span!(
+        parent: connection.span(),
+        "packet",
+        r#type = packet.name(),
+        key = field::Empty
+    );
+
+    if let Packet::Subscribe { id: key } | Packet::Update { id: key, .. } = &packet {
+        span.record("key", key.as_str());
+    }
+
+    async move {
+        debug!("Handling packet");
+
+        let authenticated = connection.is_authenticated();
+
+        match packet {
+            Packet::Authenticate { user, secret } => {
+                if credentials.is_none() {
+                    return connection.send_ok().await;
+                }
+
+                if authenticated {
+                    connection
+                        .send_err(PACKET_AUTH_ERR, "Already authenticated.")
+                        .await;
+                    return;
+                }
+
+                let name = user.clone();
+                let verified = verify_blocking(credentials, move |credentials| {
+                    credentials.authenticate(&name, &secret)
+                });
+
+                match verified.await {
+                    Some(principal) => {
+                        info!(principal = %principal.name, "Authenticated");
+
+                        if limits.unbatched.contains(&principal.name) {
+                            connection.disable_batching();
+                        }
+
+                        let grants = grants_for(acl, &principal);
+                        connection.authenticate(principal, grants);
+                        connection.send_ok().await;
+                    }
+                    None => {
+                        warn!(user = %user, "Authentication failed");
+
+                        connection
+                            .send_err(PACKET_AUTH_ERR, "Invalid credentials.")
+                            .await;
+                        disconnect(errors, id, "Authentication failed.");
+                    }
+                }
+            }
+            _ if !authenticated => {
+                connection
+                    .send_err(PACKET_UNAUTHENTICATED_ERR, "Not authenticated.")
+                    .await;
+                disconnect(errors, id, "Packet before authentication.");
+            }
+            Packet::Subscribe { id } => {
+                // Patterns are matched literally, so "plant/**" covers "plant/line1/*" but not "**".
+                // Updates are checked against the read-grants again before they are sent out.
+                if !connection.allows(Permission::Subscribe, id.as_str()) {
+                    connection
+                        .send_err(PACKET_PERMISSION_ERR, "Not allowed to subscribe to pattern.")
+                        .await;
+                    return;
+                }
+
+                let result = connection.subscription_set().insert_point(id.as_str());
+
+                match result {
+                    Ok(_) => {
+                        // A connection-error removes the connection before its subscribers, while
+                        // the connections are held it can't do so in between.
+                        {
+                            let connections = connections.read().unwrap();
+
+                            if connections.get(&connection.id).is_some() {
+                                subscriptions
+                                    .write()
+                                    .unwrap()
+                                    .insert(id.as_str(), Subscriber::Connection(connection.id));
+                            }
+                        }
+
+                        connection.send_ok().await
+                    }
+                    Err(e) => {
+                        connection
+                            .send_err(PACKET_SUBSCRIPTION_ERR, &e.to_string())
+                            .await
+                    }
+                };
+            }
+            Packet::RegisterSchema { schema } => {
+                let namespaces = match parse(&schema) {
+                    Ok(namespaces) => namespaces,
+                    Err(e) => {
+                        connection.send_err(PACKET_SCHEMA_ERR, &e.to_string()).await;
+                        return;
+                    }
+                };
+
+                let denied = namespaces
+                    .iter()
+                    .flat_map(|n| &n.points)
+                    .find(|p| !connection.allows(Permission::Register, &p.full_name));
+
+                if let Some(point) = denied {
+                    let message = format!("Not allowed to register point {}.", &point.full_name);
+                    connection.send_err(PACKET_PERMISSION_ERR, &message).await;
+                    return;
+                }
+
+                connection.set_schema(schema);
+
+                let built = build_schema(store, connections, &schemas.lock().unwrap());
+
+                if let Err(e) = built {
+                    connection.send_err(PACKET_SCHEMA_ERR, &e.to_string()).await;
+                } else {
+                    connection.send_ok().await;
+                }
+            }
+            Packet::Update { id, new_value } => {
+                if !connection.allows(Permission::Write, id.as_str()) {
+                    connection
+                        .send_err(PACKET_PERMISSION_ERR, "Not allowed to write point.")
+                        .await;
+                    return;
+                }
+
+                // Should the connection be subscribed to the point, it is answered first.
+                let held = connection.hold_updates();
+
+                match write_point(state, &id, new_value).await {
+                    Ok(_) => connection.send_ok().await,
+                    Err(e) => connection.send_err(PACKET_UPDATE_ERR, &e.to_string()).await,
+                }
+
+                drop(held);
+            }
+            Packet::Error {
+                code: _,
+                message: _,
+            } => {
+                // In this case we emit a disconnect.
+                disconnect(errors, id, "Client error.");
+            }
+            _ => {}
         }
-        _ => {}
     }
+    .instrument(span)
+    .await
+}
+
+/// The schemas of all connections and those registered through the gateway make up the schema.
+/// Takes the locked gateway-schemas, so only one rebuild happens at a time.
+fn build_schema(
+    store: &RocksDBStore,
+    connections: &RwLock<Connections>,
+    schemas: &GatewaySchemas,
+) -> Result<(), String> {
+    let connection_schemas: Vec<String> = connections
+        .read()
+        .unwrap()
+        .iter()
+        .filter_map(|c| c.get_schema())
+        .collect();
+
+    store
+        .build_schema(connection_schemas.into_iter().chain(schemas.values().cloned()))
+        .map_err(|e| e.to_string())
+}
+
+/// Stores the value and queues it for the subscribers. The point stays locked until then, so
+/// they get the updates of a point in the order they were stored.
+async fn write_point(state: &State, id: &StringKey, new_value: Value) -> Result<Value, Error> {
+    let _locked = state.points.lock(id).await;
+    let value = state.store.update_point(id, new_value).await?;
+
+    point_update(state, (id.clone(), value.clone()));
+
+    Ok(value)
 }
 
-pub fn connection_error((_, connections, _, _): EventContext, (id, e): ConnectionErrorEvent) {
-    println!("Connection-error {:?}", e);
+fn disconnect(errors: &ErrorTx, id: ConnectionId, reason: &str) {
+    let tx = errors.clone();
+    let msg = (id, Error::new(ErrorKind::ConnectionAborted, reason));
 
-    match e.kind() {
+    // Sent from a task, so neither a read-loop nor a write waits while the queue is full.
+    tokio::spawn(async move {
+        let _ = tx.send(msg).await;
+    });
+}
+
+pub fn connection_error(state: &State, (id, e): ConnectionErrorEvent) {
+    let connection = match state.connections.read().unwrap().get(&id) {
+        Some(c) => c.clone(),
+        None => return,
+    };
+
+    let span = connection.span().clone();
+    let _entered = span.enter();
+
+    let remove = match e.kind() {
+        ErrorKind::TimedOut => {
+            let authenticated = connection.is_authenticated();
+
+            if !authenticated {
+                info!(reason = %e, "Removing unauthenticated connection");
+            }
+
+            !authenticated
+        }
         ErrorKind::ConnectionReset
         | ErrorKind::ConnectionAborted
         | ErrorKind::ConnectionRefused
         | ErrorKind::UnexpectedEof => {
-            println!("Removing connection {}", &id);
-            if let Some(idx) = connections.iter().position(|c| c.id.eq(&id)) {
-                connections.remove(idx);
+            info!(reason = %e, "Removing connection");
+            true
+        }
+        _ => {
+            warn!(error = %e, "Connection-error");
+            false
+        }
+    };
+
+    if remove {
+        let mut connections = state.connections.write().unwrap();
+        connections.remove(&id);
+
+        state
+            .subscriptions
+            .write()
+            .unwrap()
+            .remove_subscriber(Subscriber::Connection(id));
+
+        gauge!("rmber_connections").set(connections.len() as f64);
+    }
+}
+
+/// Queues the update for every subscriber, see `Limits::slow_consumer` for subscribers whose
+/// queue is full.
+fn point_update(state: &State, (id, new_value): PointUpdateEvent) {
+    let started = Instant::now();
+    let _entered = debug_span!("update", key = id.as_str()).entered();
+
+    // Encoded once, on the first connection, and shared by all others.
+    let mut encoded: Option<(Bytes, Bytes)> = None;
+    let mut closed = vec![];
+
+    let subscribers = state.subscriptions.read().unwrap().lookup(id.as_str());
+    let connections = state.connections.read().unwrap();
+    let streams = state.streams.read().unwrap();
+
+    for subscriber in subscribers {
+        match subscriber {
+            Subscriber::Connection(connection_id) => {
+                let connection = match connections.get(&connection_id) {
+                    Some(c) if c.wants(id.as_str()) => c,
+                    _ => continue,
+                };
+
+                let (key, packet) = encoded.get_or_insert_with(|| {
+                    (
+                        Bytes::copy_from_slice(id.as_slice()),
+                        encode_update(&id, &new_value),
+                    )
+                });
+
+                // The write-loop of the connection records the fan-out time.
+                if connection.push_update(key, packet, started) == Delivery::Overflow {
+                    let _entered = connection.span().enter();
+                    warn!("Updates are not read fast enough, disconnecting");
+
+                    counter!("rmber_dropped_updates_total", "reason" => "queue_full").increment(1);
+                    counter!("rmber_slow_consumer_disconnects_total").increment(1);
+                    disconnect(&state.errors, connection_id, "Slow consumer.");
+                }
+            }
+            Subscriber::Stream(stream_id) => {
+                let stream = match streams.get(&stream_id) {
+                    Some(s) if s.wants(id.as_str()) => s,
+                    _ => continue,
+                };
+
+                match stream.send((id.clone(), new_value.clone())) {
+                    Delivery::Queued => {
+                        histogram!("rmber_fanout_seconds").record(started.elapsed())
+                    }
+                    Delivery::Closed => {
+                        counter!("rmber_dropped_updates_total", "reason" => "closed").increment(1);
+                        closed.push(stream_id);
+                    }
+                    _ => counter!("rmber_dropped_updates_total", "reason" => "queue_full")
+                        .increment(1),
+                }
             }
         }
-        _ => {}
+    }
+
+    drop((connections, streams));
+
+    if !closed.is_empty() {
+        let mut streams = state.streams.write().unwrap();
+        let mut index = state.subscriptions.write().unwrap();
+
+        for stream_id in closed {
+            streams.remove(&stream_id);
+            index.remove_subscriber(Subscriber::Stream(stream_id));
+        }
+
+        gauge!("rmber_streams").set(streams.len() as f64);
     }
 }
 
-pub fn point_update(
-    (_, connections, _, _): EventContext<'_>,
-    (id, new_value): PointUpdateEvent,
-) {
-    // TODO: If there are a lot of connections, this wouldn't really be performant.
-    for connection in connections {
-        let subset = connection.subscription_set();
-
-        if subset.matches(id.as_str()) {
-            let writer = connection.writer();
-
-            // TODO: How to not clone this here.
-            let packet = Packet::<StringKey>::Update {
-                id: id.clone(),
-                new_value: new_value.clone(),
+pub async fn handle_request(state: Arc<State>, (request, reply): RequestEvent) {
+    let response = respond(&state, request).await;
+
+    // The client may have gone away in the meantime.
+    let _ = reply.send(response);
+}
+
+async fn respond(state: &State, Request { session, action }: Request) -> Result<Reply, Rejection> {
+    let store = &state.store;
+    let grants = &session.grants;
+
+    match action {
+        Action::Get(key) => {
+            let key = StringKey::new(&key)?;
+
+            if !grants.allows(Permission::Read, key.as_str()) {
+                return Err(Rejection::Forbidden(String::from(
+                    "Not allowed to read point.",
+                )));
+            }
+
+            let value = store.get_point(&key).await?;
+
+            Ok(Reply::Point(PointValue {
+                id: key.get_string(),
+                value,
+            }))
+        }
+        Action::Update(key, input) => {
+            let key = StringKey::new(&key)?;
+
+            if !grants.allows(Permission::Write, key.as_str()) {
+                return Err(Rejection::Forbidden(String::from(
+                    "Not allowed to write point.",
+                )));
+            }
+
+            let schema = store.schema();
+            let point = match schema.get(key.as_str()) {
+                Some(point) => point,
+                None => return Err(Rejection::NotFound(String::from("Invalid point."))),
+            };
+
+            // Typed values are checked by update_point, like the value of a packet.
+            let value = match input {
+                Input::Json(json) => values::from_json(&json, &point.types),
+                Input::Text(text) => values::from_text(&text, &point.types),
+                Input::Typed(value) => Some(value),
+            };
+
+            let value = match value {
+                Some(value) => value,
+                None => return Err(Rejection::Invalid(String::from("Invalid point-type."))),
             };
 
-            tokio::spawn(async move {
-                let mut writer = writer.lock().await;
+            let value = write_point(state, &key, value).await?;
 
-                match packet.write_to(&mut *writer).await {
-                    Ok(_) => {}
-                    Err(_) => {},
+            Ok(Reply::Point(PointValue {
+                id: key.get_string(),
+                value: Some(value),
+            }))
+        }
+        Action::Query(query) => {
+            let mut names: Vec<String> = store
+                .schema()
+                .query(&query)
+                .map_err(|e| Rejection::Invalid(e.to_string()))?
+                .iter()
+                .map(|p| p.full_name.clone())
+                .filter(|name| grants.allows(Permission::Read, name))
+                .collect();
+
+            names.sort();
+
+            let mut points = vec![];
+
+            for id in names {
+                let key = StringKey::new(&id)?;
+                let value = store.get_point(&key).await.ok().flatten();
+
+                points.push(PointValue { id, value });
+            }
+
+            Ok(Reply::Points(points))
+        }
+        Action::Schema => {
+            let mut points: Vec<_> = store
+                .schema()
+                .query("**")
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|p| grants.allows(Permission::Read, &p.full_name))
+                .map(|p| PointInfo {
+                    id: p.full_name.clone(),
+                    namespace: p.namespace.clone(),
+                    name: p.name.clone(),
+                    types: PointType::ALL
+                        .iter()
+                        .filter(|t| p.types.contains(t))
+                        .copied()
+                        .collect(),
+                })
+                .collect();
+
+            points.sort_by(|a, b| a.id.cmp(&b.id));
+
+            Ok(Reply::Schema(points))
+        }
+        Action::RegisterSchema(schema) => {
+            let namespaces = parse(&schema).map_err(|e| Rejection::Invalid(e.to_string()))?;
+
+            let denied = namespaces
+                .iter()
+                .flat_map(|n| &n.points)
+                .find(|p| !grants.allows(Permission::Register, &p.full_name));
+
+            if let Some(point) = denied {
+                let message = format!("Not allowed to register point {}.", &point.full_name);
+                return Err(Rejection::Forbidden(message));
+            }
+
+            // A principal's schema replaces the one it registered before.
+            let name = session.principal.name.clone();
+            let mut schemas = state.schemas.lock().unwrap();
+            let previous = schemas.insert(name.clone(), schema);
+
+            if let Err(e) = build_schema(store, &state.connections, &schemas) {
+                match previous {
+                    Some(previous) => schemas.insert(name, previous),
+                    None => schemas.remove(&name),
                 };
-            });
+
+                return Err(Rejection::Invalid(e));
+            }
+
+            Ok(Reply::Done)
+        }
+        Action::Subscribe { stream, pattern, tx } => {
+            // Checked like the pattern of a Subscribe-packet.
+            if !grants.allows(Permission::Subscribe, &pattern) {
+                return Err(Rejection::Forbidden(String::from(
+                    "Not allowed to subscribe to pattern.",
+                )));
+            }
+
+            let mut streams = state.streams.write().unwrap();
+            let mut index = state.subscriptions.write().unwrap();
+
+            remove_closed(&mut streams, &mut index);
+
+            let result = match streams.get_mut(&stream) {
+                Some(existing) => existing.subscription_set().insert_point(&pattern),
+                None => QuerySet::single(&pattern).map(|subscriptions| {
+                    streams.insert(stream, EventStream::new(subscriptions, grants.clone(), tx));
+                }),
+            };
+
+            if result.is_ok() {
+                index.insert(&pattern, Subscriber::Stream(stream));
+            }
+
+            gauge!("rmber_streams").set(streams.len() as f64);
+
+            match result {
+                Ok(_) => Ok(Reply::Done),
+                Err(e) => Err(Rejection::Invalid(e.to_string())),
+            }
+        }
+        Action::Unsubscribe { stream, pattern } => {
+            let mut streams = state.streams.write().unwrap();
+
+            if let Some(existing) = streams.get_mut(&stream) {
+                if let Err(e) = existing.subscription_set().remove_point(&pattern) {
+                    return Err(Rejection::Invalid(e.to_string()));
+                }
+
+                state
+                    .subscriptions
+                    .write()
+                    .unwrap()
+                    .remove(&pattern, Subscriber::Stream(stream));
+            }
+
+            Ok(Reply::Done)
         }
     }
 }
 
-pub fn server_error(_: EventContext, event: ServerErrorEvent) {
-    println!("Server-error {:?}", event);
+/// A stream is only found to be closed when it gets an update, so the others are dropped whenever
+/// a new one subscribes.
+fn remove_closed(streams: &mut EventStreams, index: &mut SubscriptionIndex) {
+    streams.retain(|&id, stream| {
+        let closed = stream.is_closed();
+
+        if closed {
+            index.remove_subscriber(Subscriber::Stream(id));
+        }
+
+        !closed
+    });
+}
+
+pub fn server_error(event: ServerErrorEvent) {
+    error!(error = ?event, "Server-error");
 }
@@ -0,0 +1,161 @@
+use axum::extract::State;
+use axum::http::{header, HeaderMap};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use protocol::Value;
+use schema::NS_DIVIDER;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::error;
+
+use crate::gateway::{Action, Gateway, PointValue, Reply};
+use crate::rest::{authorization, serve_router, Rejected};
+
+const METRIC: &str = "rmber_point_value";
+
+#[derive(Clone)]
+struct ExporterState {
+    gateway: Gateway,
+    query: String,
+}
+
+/// Serves `GET /metrics` with the current values of the points matching `query` as gauges, for
+/// Prometheus to scrape. Scrapers authenticate like HTTP-clients and only see points they may
+/// read.
+pub async fn serve(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    query: String,
+    gateway: Gateway,
+) {
+    let timeout = gateway.limits().auth_timeout;
+    let router = Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(ExporterState { gateway, query });
+
+    if let Err(e) = serve_router(listener, tls, timeout, router).await {
+        error!(error = %e, "Exporter stopped");
+    }
+}
+
+async fn metrics(
+    State(state): State<ExporterState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, Rejected> {
+    let session = state
+        .gateway
+        .authenticate_header(authorization(&headers))
+        .await?;
+    let action = Action::Query(state.query.clone());
+
+    let points = match state.gateway.request(&session, action).await? {
+        Reply::Points(points) => points,
+        _ => vec![],
+    };
+
+    let content_type = "text/plain; version=0.0.4";
+
+    Ok(([(header::CONTENT_TYPE, content_type)], render(&points)))
+}
+
+/// One gauge per point, labeled with the segments of its namespace and its name, e.g.
+/// `rmber_point_value{ns0="plant",ns1="line1",point="speed"} 42`. Strings, blobs and points
+/// that were never written are left out.
+fn render(points: &[PointValue]) -> String {
+    let mut out = format!(
+        "# HELP {} Current value of a point, booleans are 0 or 1.\n# TYPE {} gauge\n",
+        METRIC, METRIC
+    );
+
+    for point in points {
+        let value = match point.value.as_ref().and_then(gauge) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        let mut segments: Vec<&str> = point.id.split(NS_DIVIDER).collect();
+        let name = segments.pop().unwrap_or_default();
+
+        let mut labels: Vec<String> = segments
+            .iter()
+            .enumerate()
+            .map(|(i, segment)| format!("ns{}=\"{}\"", i, escape(segment)))
+            .collect();
+        labels.push(format!("point=\"{}\"", escape(name)));
+
+        out.push_str(&format!("{}{{{}}} {}\n", METRIC, labels.join(","), value));
+    }
+
+    out
+}
+
+fn gauge(value: &Value) -> Option<String> {
+    let value = match value {
+        Value::Boolean(v) => (*v as u8).to_string(),
+        Value::U8(v) => v.to_string(),
+        Value::I8(v) => v.to_string(),
+        Value::U16(v) => v.to_string(),
+        Value::I16(v) => v.to_string(),
+        Value::U32(v) => v.to_string(),
+        Value::I32(v) => v.to_string(),
+        Value::U64(v) => v.to_string(),
+        Value::I64(v) => v.to_string(),
+        Value::F32(v) => float(*v as f64),
+        Value::F64(v) => float(*v),
+        Value::String(_) | Value::Blob(_) => return None,
+    };
+
+    Some(value)
+}
+
+fn float(value: f64) -> String {
+    match value {
+        v if v.is_nan() => String::from("NaN"),
+        v if v == f64::INFINITY => String::from("+Inf"),
+        v if v == f64::NEG_INFINITY => String::from("-Inf"),
+        v => v.to_string(),
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(id: &str, value: Option<Value>) -> PointValue {
+        PointValue {
+            id: String::from(id),
+            value,
+        }
+    }
+
+    #[test]
+    fn renders_gauges() {
+        let points = vec![
+            point("plant/line1/speed", Some(Value::I32(-42))),
+            point("plant/on", Some(Value::Boolean(true))),
+            point("plant/tag", Some(Value::String(String::from("a")))),
+            point("plant/temp", Some(Value::F64(f64::NAN))),
+            point("plant/idle", None),
+        ];
+
+        let rendered = render(&points);
+        let samples: Vec<&str> = rendered.lines().filter(|l| !l.starts_with('#')).collect();
+
+        assert_eq!(
+            samples,
+            vec![
+                "rmber_point_value{ns0=\"plant\",ns1=\"line1\",point=\"speed\"} -42",
+                "rmber_point_value{ns0=\"plant\",point=\"on\"} 1",
+                "rmber_point_value{ns0=\"plant\",point=\"temp\"} NaN",
+            ]
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use protocol::Value;
+use schema::{PointType, QuerySet};
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender, UnboundedSender};
+use tokio::sync::oneshot;
+
+use crate::acl::{grants_for, Acl, Grants};
+use crate::auth::{verify_blocking, Credentials, Principal, VerifiedHeaders};
+use crate::config::Limits;
+use crate::connection::wants;
+use crate::outbound::Delivery;
+use crate::server::{PointUpdateEvent, RequestEvent};
+
+pub type RequestTx = UnboundedSender<RequestEvent>;
+pub type UpdateTx = Sender<PointUpdateEvent>;
+pub type UpdateRx = Receiver<PointUpdateEvent>;
+pub type StreamId = u64;
+
+/// A value as a front-end received it. The server converts it with the types of the point.
+#[derive(Debug)]
+pub enum Input {
+    /// Takes the first of the point's types it fits into, see `values::from_json`.
+    Json(serde_json::Value),
+    /// Text like `42` or `true`, strings and blobs are taken as-is, see `values::from_text`.
+    Text(Vec<u8>),
+    /// Already typed, only checked against the point's types.
+    Typed(Value),
+}
+
+#[derive(Debug)]
+pub enum Action {
+    /// The value of a single point.
+    Get(String),
+    Update(String, Input),
+    /// All points matching the glob, with their values.
+    Query(String),
+    /// Every point of the schema and its types.
+    Schema,
+    /// Replaces the schema the principal registered before, see `GatewaySchemas`.
+    RegisterSchema(String),
+    /// Adds a pattern to the stream, creating it on first use. Matching updates are sent to `tx`.
+    Subscribe {
+        stream: StreamId,
+        pattern: String,
+        tx: UpdateTx,
+    },
+    Unsubscribe {
+        stream: StreamId,
+        pattern: String,
+    },
+}
+
+/// A request forwarded to the server, which handles it in a task of its own.
+#[derive(Debug)]
+pub struct Request {
+    pub session: Session,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PointValue {
+    pub id: String,
+    /// `None` for points that were never written.
+    pub value: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PointInfo {
+    pub id: String,
+    pub namespace: String,
+    pub name: String,
+    /// In the order of `PointType::ALL`.
+    pub types: Vec<PointType>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Point(PointValue),
+    Points(Vec<PointValue>),
+    Schema(Vec<PointInfo>),
+    Done,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    Unauthenticated(String),
+    Forbidden(String),
+    NotFound(String),
+    Invalid(String),
+    Unavailable(String),
+}
+
+impl Rejection {
+    pub fn message(&self) -> &str {
+        match self {
+            Rejection::Unauthenticated(m)
+            | Rejection::Forbidden(m)
+            | Rejection::NotFound(m)
+            | Rejection::Invalid(m)
+            | Rejection::Unavailable(m) => m,
+        }
+    }
+}
+
+impl From<std::io::Error> for Rejection {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::NotFound => Rejection::NotFound(e.to_string()),
+            ErrorKind::PermissionDenied => Rejection::Forbidden(e.to_string()),
+            ErrorKind::InvalidData | ErrorKind::InvalidInput => Rejection::Invalid(e.to_string()),
+            _ => Rejection::Unavailable(e.to_string()),
+        }
+    }
+}
+
+/// An authenticated client of a front-end.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub principal: Principal,
+    pub grants: Arc<Grants>,
+}
+
+/// Shared by the front-ends that don't speak the native protocol: HTTP, MQTT, ... They
+/// authenticate their clients themselves and send everything else to the server.
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    tx: RequestTx,
+    credentials: Arc<Option<Credentials>>,
+    verified: Arc<VerifiedHeaders>,
+    acl: Arc<Option<Acl>>,
+    limits: Limits,
+}
+
+impl Gateway {
+    pub fn new(
+        tx: RequestTx,
+        credentials: Arc<Option<Credentials>>,
+        acl: Arc<Option<Acl>>,
+        limits: Limits,
+    ) -> Self {
+        Gateway {
+            tx,
+            credentials,
+            verified: Arc::new(VerifiedHeaders::default()),
+            acl,
+            limits,
+        }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// For `Action::Subscribe`, holds `Limits::outbound_queue` updates. Further ones are dropped
+    /// until the client catches up.
+    pub fn update_channel(&self) -> (UpdateTx, UpdateRx) {
+        channel(self.limits.outbound_queue)
+    }
+
+    fn session(&self, principal: Principal) -> Session {
+        let grants = grants_for(&self.acl, &principal);
+
+        Session { principal, grants }
+    }
+
+    /// Like the handshake of a connection: without credentials everyone is anonymous, an empty
+    /// `user` means `secret` is a bearer-token.
+    pub async fn authenticate(&self, user: &str, secret: &str) -> Result<Session, Rejection> {
+        if self.credentials.is_none() {
+            return Ok(self.session(Principal::anonymous()));
+        }
+
+        let (user, secret) = (String::from(user), String::from(secret));
+        let verified = verify_blocking(&self.credentials, move |credentials| {
+            credentials.authenticate(&user, &secret)
+        });
+
+        match verified.await {
+            Some(principal) => Ok(self.session(principal)),
+            None => Err(Rejection::Unauthenticated(String::from(
+                "Invalid credentials.",
+            ))),
+        }
+    }
+
+    /// For clients that sent no credentials, or were identified by the transport.
+    pub fn authenticate_principal(
+        &self,
+        principal: Option<Principal>,
+    ) -> Result<Session, Rejection> {
+        match (principal, &*self.credentials) {
+            (Some(principal), _) => Ok(self.session(principal)),
+            (None, None) => Ok(self.session(Principal::anonymous())),
+            (None, Some(_)) => Err(Rejection::Unauthenticated(String::from(
+                "Not authenticated.",
+            ))),
+        }
+    }
+
+    /// An HTTP `Authorization` header, see `Credentials::authenticate_header`. Verified passwords
+    /// are remembered for a while, see `VerifiedHeaders`.
+    pub async fn authenticate_header(&self, header: Option<&str>) -> Result<Session, Rejection> {
+        let header = match (&*self.credentials, header) {
+            (Some(_), Some(header)) => String::from(header),
+            _ => return self.authenticate_principal(None),
+        };
+
+        let basic = header.starts_with("Basic ");
+
+        if basic {
+            if let Some(principal) = self.verified.get(&header) {
+                return Ok(self.session(principal));
+            }
+        }
+
+        let verified = verify_blocking(&self.credentials, {
+            let header = header.clone();
+            move |credentials| credentials.authenticate_header(&header)
+        });
+
+        match verified.await {
+            Some(principal) => {
+                if basic {
+                    self.verified.insert(&header, principal.clone());
+                }
+
+                Ok(self.session(principal))
+            }
+            None => Err(Rejection::Unauthenticated(String::from(
+                "Invalid credentials.",
+            ))),
+        }
+    }
+
+    pub async fn request(&self, session: &Session, action: Action) -> Result<Reply, Rejection> {
+        let request = Request {
+            session: session.clone(),
+            action,
+        };
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        if self.tx.send((request, reply_tx)).is_err() {
+            return Err(Rejection::Unavailable(String::from(
+                "Server is shutting down.",
+            )));
+        }
+
+        match reply_rx.await {
+            Ok(reply) => reply,
+            Err(_) => Err(Rejection::Unavailable(String::from("Request was dropped."))),
+        }
+    }
+}
+
+/// The subscriptions of a front-end client, registered with the server like a connection.
+pub struct EventStream {
+    subscriptions: QuerySet,
+    grants: Arc<Grants>,
+    tx: UpdateTx,
+}
+
+impl EventStream {
+    pub fn new(subscriptions: QuerySet, grants: Arc<Grants>, tx: UpdateTx) -> Self {
+        EventStream {
+            subscriptions,
+            grants,
+            tx,
+        }
+    }
+
+    pub fn subscription_set(&mut self) -> &mut QuerySet {
+        &mut self.subscriptions
+    }
+
+    pub fn wants(&self, id: &str) -> bool {
+        wants(&self.subscriptions, &self.grants, id)
+    }
+
+    /// Never waits, the update is dropped if the client is behind by a full queue.
+    pub fn send(&self, update: PointUpdateEvent) -> Delivery {
+        match self.tx.try_send(update) {
+            Ok(_) => Delivery::Queued,
+            Err(TrySendError::Full(_)) => Delivery::Dropped,
+            Err(TrySendError::Closed(_)) => Delivery::Closed,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+/// Ids for `Action::Subscribe`, unique within the process.
+pub fn next_stream_id() -> StreamId {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Escapes glob-characters, so the pattern only matches the point itself.
+pub fn escape_pattern(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+
+    for c in literal.chars() {
+        if "*?[]{}\\".contains(c) {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
@@ -0,0 +1,464 @@
+use async_graphql::http::ALL_WEBSOCKET_PROTOCOLS;
+use async_graphql::{
+    Context, Data, Error, ErrorExtensions, Json, Object, Result, Schema, Subscription,
+};
+use async_graphql_axum::{GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLWebSocket};
+use axum::extract::{State, WebSocketUpgrade};
+use axum::http::HeaderMap;
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use protocol::Value;
+use schema::{PointType, QuerySet, NS_DIVIDER};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use store::to_point_type;
+use tokio::sync::OnceCell;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::gateway::{
+    next_stream_id, Action, Gateway, Input, PointInfo, Rejection, Reply, Session,
+};
+use crate::rest::{authorization, Rejected};
+use crate::values;
+
+pub type GraphSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+#[derive(Clone)]
+struct GraphState {
+    gateway: Gateway,
+    schema: GraphSchema,
+}
+
+/// `POST /graphql` for queries and mutations, `GET /graphql` upgrades to a WebSocket for
+/// subscriptions. Clients authenticate like with the other HTTP-routes, WebSocket-clients may
+/// also send an `authorization` in the payload of `connection_init`.
+pub fn router(gateway: Gateway) -> Router {
+    let schema = Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(gateway.clone())
+        .finish();
+
+    Router::new()
+        .route("/graphql", get(subscriptions).post(execute))
+        .with_state(GraphState { gateway, schema })
+}
+
+async fn execute(
+    State(state): State<GraphState>,
+    headers: HeaderMap,
+    request: GraphQLRequest,
+) -> Result<GraphQLResponse, Rejected> {
+    let session = state
+        .gateway
+        .authenticate_header(authorization(&headers))
+        .await?;
+    let request = request.into_inner().data(session);
+
+    Ok(state.schema.execute(request).await.into())
+}
+
+async fn subscriptions(
+    State(state): State<GraphState>,
+    headers: HeaderMap,
+    protocol: GraphQLProtocol,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let header = authorization(&headers).map(String::from);
+
+    upgrade
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |socket| {
+            let gateway = state.gateway.clone();
+
+            GraphQLWebSocket::new(socket, state.schema, protocol)
+                .on_connection_init(move |payload| async move {
+                    let header = header.or_else(|| {
+                        ["authorization", "Authorization"]
+                            .iter()
+                            .find_map(|key| payload.get(key)?.as_str().map(String::from))
+                    });
+
+                    let session = gateway
+                        .authenticate_header(header.as_deref())
+                        .await
+                        .map_err(to_error)?;
+
+                    let mut data = Data::default();
+                    data.insert(session);
+
+                    Ok(data)
+                })
+                .serve()
+        })
+}
+
+async fn request(ctx: &Context<'_>, action: Action) -> Result<Reply> {
+    let gateway = ctx.data::<Gateway>()?;
+    let session = ctx.data::<Session>()?;
+
+    gateway.request(session, action).await.map_err(to_error)
+}
+
+/// The points the client may read, the namespace-tree is derived from their namespaces.
+async fn schema_points(ctx: &Context<'_>) -> Result<Arc<Vec<PointInfo>>> {
+    match request(ctx, Action::Schema).await? {
+        Reply::Schema(points) => Ok(Arc::new(points)),
+        _ => Err(Error::new("Unexpected reply.")),
+    }
+}
+
+fn to_error(rejection: Rejection) -> Error {
+    let code = match &rejection {
+        Rejection::Unauthenticated(_) => "UNAUTHENTICATED",
+        Rejection::Forbidden(_) => "FORBIDDEN",
+        Rejection::NotFound(_) => "NOT_FOUND",
+        Rejection::Invalid(_) => "BAD_USER_INPUT",
+        Rejection::Unavailable(_) => "UNAVAILABLE",
+    };
+
+    Error::new(rejection.message()).extend_with(|_, e| e.set("code", code))
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// The top-level namespaces, nested ones are reached through their parents.
+    async fn namespaces(&self, ctx: &Context<'_>) -> Result<Vec<Namespace>> {
+        Ok(children(&schema_points(ctx).await?, None))
+    }
+
+    /// A namespace by its path, e.g. `plant/line1`.
+    async fn namespace(&self, ctx: &Context<'_>, path: String) -> Result<Option<Namespace>> {
+        let all = schema_points(ctx).await?;
+        let prefix = format!("{}{}", path, NS_DIVIDER);
+
+        let exists = all
+            .iter()
+            .any(|p| p.namespace == path || p.namespace.starts_with(&prefix));
+
+        Ok(match exists {
+            true => Some(Namespace { path, all }),
+            false => None,
+        })
+    }
+
+    /// A point by its full name, e.g. `plant/line1/speed`.
+    async fn point(&self, ctx: &Context<'_>, id: String) -> Result<Option<Point>> {
+        let all = schema_points(ctx).await?;
+
+        Ok(all.iter().find(|p| p.id == id).cloned().map(Point::new))
+    }
+
+    /// All points matching the glob.
+    async fn points(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = "**")] query: String,
+    ) -> Result<Vec<Point>> {
+        let query = QuerySet::single(&query).map_err(|e| Error::new(e.to_string()))?;
+        let all = schema_points(ctx).await?;
+
+        Ok(all
+            .iter()
+            .filter(|p| query.matches(&p.id))
+            .cloned()
+            .map(Point::new)
+            .collect())
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    /// Like `PUT /points/{ns}/{point}`: without a type the value takes the first of the point's
+    /// types it fits into.
+    async fn update(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+        value: Json<serde_json::Value>,
+        #[graphql(name = "type")] point_type: Option<String>,
+    ) -> Result<PointUpdate> {
+        let input = match point_type {
+            Some(point_type) => PointType::from_name(&point_type)
+                .and_then(|t| values::convert(&value, t))
+                .map(Input::Typed)
+                .ok_or_else(|| to_error(Rejection::Invalid(String::from("Invalid point-type."))))?,
+            None => Input::Json(value.0),
+        };
+
+        match request(ctx, Action::Update(id, input)).await? {
+            Reply::Point(point) => Ok(PointUpdate {
+                id: point.id,
+                value: point.value,
+            }),
+            _ => Err(Error::new("Unexpected reply.")),
+        }
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Every update of a point matching the glob.
+    async fn updates(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = "**")] query: String,
+    ) -> Result<impl Stream<Item = PointUpdate>> {
+        let (tx, rx) = ctx.data::<Gateway>()?.update_channel();
+
+        let subscribe = Action::Subscribe {
+            stream: next_stream_id(),
+            pattern: query,
+            tx,
+        };
+
+        request(ctx, subscribe).await?;
+
+        Ok(ReceiverStream::new(rx).map(|(id, value)| PointUpdate {
+            id: id.get_string(),
+            value: Some(value),
+        }))
+    }
+}
+
+pub struct Namespace {
+    path: String,
+    all: Arc<Vec<PointInfo>>,
+}
+
+#[Object]
+impl Namespace {
+    /// The last part of the path.
+    async fn name(&self) -> &str {
+        self.path.rsplit(NS_DIVIDER).next().unwrap_or(&self.path)
+    }
+
+    async fn path(&self) -> &str {
+        &self.path
+    }
+
+    async fn points(&self) -> Vec<Point> {
+        self.all
+            .iter()
+            .filter(|p| p.namespace == self.path)
+            .cloned()
+            .map(Point::new)
+            .collect()
+    }
+
+    async fn namespaces(&self) -> Vec<Namespace> {
+        children(&self.all, Some(&self.path))
+    }
+}
+
+/// The namespaces directly below `parent`, or the top-level ones. Namespaces without points of
+/// their own exist as long as a nested one has points.
+fn children(all: &Arc<Vec<PointInfo>>, parent: Option<&str>) -> Vec<Namespace> {
+    let mut paths = BTreeSet::new();
+
+    for point in all.iter() {
+        let mut path = String::new();
+
+        for part in point.namespace.split(NS_DIVIDER) {
+            let own_parent = path.clone();
+
+            if !path.is_empty() {
+                path.push_str(NS_DIVIDER);
+            }
+            path.push_str(part);
+
+            let is_child = match parent {
+                Some(parent) => own_parent == parent,
+                None => own_parent.is_empty(),
+            };
+
+            if is_child {
+                paths.insert(path.clone());
+            }
+        }
+    }
+
+    paths
+        .into_iter()
+        .map(|path| Namespace {
+            path,
+            all: all.clone(),
+        })
+        .collect()
+}
+
+pub struct Point {
+    info: PointInfo,
+    /// Only read from the store if the client asks for the value.
+    value: OnceCell<Option<Value>>,
+}
+
+impl Point {
+    fn new(info: PointInfo) -> Self {
+        Point {
+            info,
+            value: OnceCell::new(),
+        }
+    }
+
+    async fn current(&self, ctx: &Context<'_>) -> Result<Option<&Value>> {
+        let value = self
+            .value
+            .get_or_try_init(|| async {
+                match request(ctx, Action::Get(self.info.id.clone())).await? {
+                    Reply::Point(point) => Ok::<_, Error>(point.value),
+                    _ => Err(Error::new("Unexpected reply.")),
+                }
+            })
+            .await?;
+
+        Ok(value.as_ref())
+    }
+}
+
+#[Object]
+impl Point {
+    /// The full name, e.g. `plant/line1/speed`.
+    async fn id(&self) -> &str {
+        &self.info.id
+    }
+
+    async fn name(&self) -> &str {
+        &self.info.name
+    }
+
+    async fn namespace(&self) -> &str {
+        &self.info.namespace
+    }
+
+    /// The types the point accepts, as written in the schema.
+    async fn types(&self) -> Vec<&'static str> {
+        self.info.types.iter().map(|t| t.as_str()).collect()
+    }
+
+    /// The type of the current value, `null` if the point was never written.
+    #[graphql(name = "type")]
+    async fn value_type(&self, ctx: &Context<'_>) -> Result<Option<&'static str>> {
+        Ok(self.current(ctx).await?.map(|v| to_point_type(v).as_str()))
+    }
+
+    /// The current value as JSON, blobs are hex-encoded.
+    async fn value(&self, ctx: &Context<'_>) -> Result<Option<Json<serde_json::Value>>> {
+        Ok(self.current(ctx).await?.map(|v| Json(values::to_json(v))))
+    }
+}
+
+/// A written value, the same as `GET /points/{ns}/{point}` returns.
+pub struct PointUpdate {
+    id: String,
+    value: Option<Value>,
+}
+
+#[Object]
+impl PointUpdate {
+    async fn id(&self) -> &str {
+        &self.id
+    }
+
+    #[graphql(name = "type")]
+    async fn value_type(&self) -> Option<&'static str> {
+        self.value.as_ref().map(|v| to_point_type(v).as_str())
+    }
+
+    async fn value(&self) -> Option<Json<serde_json::Value>> {
+        self.value.as_ref().map(|v| Json(values::to_json(v)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::Listener;
+    use crate::testing::{http, producer, secured, spawn_server};
+    use serde_json::json;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    fn point(id: &str) -> PointInfo {
+        let (namespace, name) = id.rsplit_once(NS_DIVIDER).unwrap();
+
+        PointInfo {
+            id: String::from(id),
+            namespace: String::from(namespace),
+            name: String::from(name),
+            types: vec![PointType::U8],
+        }
+    }
+
+    #[test]
+    fn namespaces_form_a_tree() {
+        let all = Arc::new(vec![
+            point("plant/speed"),
+            point("plant/line1/belt/speed"),
+            point("plant/line2/on"),
+            point("office/temp"),
+        ]);
+
+        let paths = |namespaces: Vec<Namespace>| -> Vec<String> {
+            namespaces.into_iter().map(|n| n.path).collect()
+        };
+
+        assert_eq!(paths(children(&all, None)), vec!["office", "plant"]);
+        assert_eq!(
+            paths(children(&all, Some("plant"))),
+            vec!["plant/line1", "plant/line2"]
+        );
+        assert_eq!(
+            paths(children(&all, Some("plant/line1"))),
+            vec!["plant/line1/belt"]
+        );
+        assert!(children(&all, Some("office")).is_empty());
+    }
+
+    async fn graphql(
+        address: SocketAddr,
+        token: Option<&str>,
+        query: &str,
+    ) -> (u16, serde_json::Value) {
+        let body = json!({ "query": query }).to_string();
+        let (status, body) = http(address, "POST /graphql", token, Some(&body)).await;
+
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn queries_and_mutations_check_the_caller() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = spawn_server(secured(vec![Listener::Http(listener)]));
+
+        let _producer = producer(&server.handle).await;
+        let update = r#"mutation { update(id: "plant/line1/speed", value: 7) { type value } }"#;
+        let line1 = r#"{ point(id: "plant/line1/speed") { value } }"#;
+        let line2 = r#"{ point(id: "plant/line2/speed") { value } }"#;
+
+        assert_eq!(graphql(address, None, line1).await.0, 401);
+
+        let (_, denied) = graphql(address, Some("dashboard-token"), update).await;
+        assert_eq!(denied["errors"][0]["extensions"]["code"], "FORBIDDEN");
+
+        let (_, updated) = graphql(address, Some("producer-token"), update).await;
+        assert_eq!(
+            updated["data"],
+            json!({ "update": { "type": "i32", "value": 7 } })
+        );
+
+        let (_, point) = graphql(address, Some("dashboard-token"), line1).await;
+        assert_eq!(point["data"], json!({ "point": { "value": 7 } }));
+
+        // Points the caller may not read are left out, like from the schema.
+        let (_, hidden) = graphql(address, Some("dashboard-token"), line2).await;
+        assert_eq!(hidden["data"], json!({ "point": null }));
+    }
+}
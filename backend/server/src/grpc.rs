@@ -0,0 +1,431 @@
+// Status is the error of every tonic-handler, boxing it in the helpers would only move the copy.
+#![allow(clippy::result_large_err)]
+
+use protocol::Value;
+use std::convert::TryFrom;
+use std::io::Error;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::{ReceiverStream, UnboundedReceiverStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::server::Connected;
+use tonic::{Request, Response, Status};
+use tracing::{error, info, warn};
+
+use crate::auth::Principal;
+use crate::gateway::{
+    next_stream_id, Action, Gateway, Input, PointValue, Rejection, Reply, Session,
+};
+use crate::listener::ACCEPT_BACKOFF;
+use crate::tls;
+
+pub mod proto {
+    tonic::include_proto!("rmber");
+}
+
+use proto::points_server::{Points, PointsServer};
+use proto::value::Value as Kind;
+
+/// Serves the gRPC-service of `proto/rmber.proto` until the listener fails. With TLS a
+/// client-certificate authenticates the client like on the native listener, otherwise the
+/// `authorization` metadata does.
+pub async fn serve(listener: TcpListener, tls: Option<TlsAcceptor>, gateway: Gateway) {
+    // gRPC-clients expect HTTP/2 to be negotiated with ALPN.
+    let tls = tls.map(|acceptor| {
+        let mut config = (**acceptor.config()).clone();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+
+        TlsAcceptor::from(Arc::new(config))
+    });
+
+    let timeout = gateway.limits().auth_timeout;
+    let (tx, rx) = unbounded_channel::<Result<Authenticated, Error>>();
+
+    // Handshakes run in their own tasks so a slow client can't hold up the accept-loop. The loop
+    // and its listener go away with the server, which drops the receiving end.
+    tokio::spawn(async move {
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = tx.closed() => break,
+            };
+
+            let (stream, address) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!(error = %e, "Could not accept gRPC-client");
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            let acceptor = match &tls {
+                Some(acceptor) => acceptor.clone(),
+                None => {
+                    if tx.send(Ok(Authenticated::new(stream, None))).is_err() {
+                        break;
+                    }
+
+                    continue;
+                }
+            };
+
+            let tx = tx.clone();
+
+            tokio::spawn(async move {
+                match tls::accept(&acceptor, stream, timeout).await {
+                    Ok((stream, principal)) => {
+                        let _ = tx.send(Ok(Authenticated::new(stream, principal)));
+                    }
+                    Err(e) => info!(peer = %address, reason = %e, "gRPC-handshake failed"),
+                }
+            });
+        }
+    });
+
+    let result = tonic::transport::Server::builder()
+        .add_service(PointsServer::new(Service { gateway }))
+        .serve_with_incoming(UnboundedReceiverStream::new(rx))
+        .await;
+
+    if let Err(e) = result {
+        error!(error = %e, "gRPC-server stopped");
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+/// A connection with the principal of its client-certificate, which requests find in their
+/// extensions.
+struct Authenticated {
+    stream: Box<dyn Io>,
+    principal: Option<Principal>,
+}
+
+impl Authenticated {
+    fn new<S: Io + 'static>(stream: S, principal: Option<Principal>) -> Self {
+        Authenticated {
+            stream: Box::new(stream),
+            principal,
+        }
+    }
+}
+
+impl Connected for Authenticated {
+    type ConnectInfo = Option<Principal>;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.principal.clone()
+    }
+}
+
+impl AsyncRead for Authenticated {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Authenticated {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+struct Service {
+    gateway: Gateway,
+}
+
+impl Service {
+    /// Like MQTT, a principal from the transport wins over the metadata.
+    async fn session<T>(&self, request: &Request<T>) -> Result<Session, Status> {
+        let principal = request
+            .extensions()
+            .get::<Option<Principal>>()
+            .cloned()
+            .flatten();
+
+        let header = request
+            .metadata()
+            .get("authorization")
+            .and_then(|h| h.to_str().ok());
+
+        let session = match principal {
+            Some(principal) => self.gateway.authenticate_principal(Some(principal)),
+            None => self.gateway.authenticate_header(header).await,
+        };
+
+        session.map_err(to_status)
+    }
+
+    async fn update(
+        &self,
+        session: &Session,
+        update: proto::UpdateRequest,
+    ) -> Result<proto::Point, Status> {
+        let value = update
+            .value
+            .ok_or_else(|| Status::invalid_argument("Missing value."))?;
+
+        let action = Action::Update(update.id, Input::Typed(from_proto(value)?));
+
+        match self.gateway.request(session, action).await {
+            Ok(Reply::Point(point)) => Ok(to_point(point)),
+            Ok(_) => Err(Status::internal("Unexpected reply.")),
+            Err(rejection) => Err(to_status(rejection)),
+        }
+    }
+}
+
+type PointStream = Pin<Box<dyn Stream<Item = Result<proto::Point, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Points for Service {
+    async fn get(
+        &self,
+        request: Request<proto::GetRequest>,
+    ) -> Result<Response<proto::Point>, Status> {
+        let session = self.session(&request).await?;
+        let action = Action::Get(request.into_inner().id);
+
+        match self.gateway.request(&session, action).await {
+            Ok(Reply::Point(point)) => Ok(Response::new(to_point(point))),
+            Ok(_) => Err(Status::internal("Unexpected reply.")),
+            Err(rejection) => Err(to_status(rejection)),
+        }
+    }
+
+    async fn update(
+        &self,
+        request: Request<proto::UpdateRequest>,
+    ) -> Result<Response<proto::Point>, Status> {
+        let session = self.session(&request).await?;
+        let point = Service::update(self, &session, request.into_inner()).await?;
+
+        Ok(Response::new(point))
+    }
+
+    async fn batch_update(
+        &self,
+        request: Request<proto::BatchUpdateRequest>,
+    ) -> Result<Response<proto::BatchUpdateResponse>, Status> {
+        let session = self.session(&request).await?;
+        let mut points = vec![];
+
+        for (index, update) in request.into_inner().updates.into_iter().enumerate() {
+            let point = Service::update(self, &session, update)
+                .await
+                .map_err(|s| Status::new(s.code(), format!("Update {}: {}", index, s.message())))?;
+
+            points.push(point);
+        }
+
+        Ok(Response::new(proto::BatchUpdateResponse { points }))
+    }
+
+    async fn register_schema(
+        &self,
+        request: Request<proto::RegisterSchemaRequest>,
+    ) -> Result<Response<proto::RegisterSchemaResponse>, Status> {
+        let session = self.session(&request).await?;
+        let action = Action::RegisterSchema(request.into_inner().schema);
+
+        match self.gateway.request(&session, action).await {
+            Ok(_) => Ok(Response::new(proto::RegisterSchemaResponse {})),
+            Err(rejection) => Err(to_status(rejection)),
+        }
+    }
+
+    async fn query(
+        &self,
+        request: Request<proto::QueryRequest>,
+    ) -> Result<Response<proto::QueryResponse>, Status> {
+        let session = self.session(&request).await?;
+        let action = Action::Query(request.into_inner().query);
+
+        match self.gateway.request(&session, action).await {
+            Ok(Reply::Points(points)) => Ok(Response::new(proto::QueryResponse {
+                points: points.into_iter().map(to_point).collect(),
+            })),
+            Ok(_) => Err(Status::internal("Unexpected reply.")),
+            Err(rejection) => Err(to_status(rejection)),
+        }
+    }
+
+    type SubscribeStream = PointStream;
+
+    /// Without patterns every point the client may read is streamed.
+    async fn subscribe(
+        &self,
+        request: Request<proto::SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let session = self.session(&request).await?;
+        let mut patterns = request.into_inner().patterns;
+
+        if patterns.is_empty() {
+            patterns.push(String::from("**"));
+        }
+
+        let stream = next_stream_id();
+        let (tx, rx) = self.gateway.update_channel();
+
+        for pattern in patterns {
+            let subscribe = Action::Subscribe {
+                stream,
+                pattern,
+                tx: tx.clone(),
+            };
+
+            self.gateway
+                .request(&session, subscribe)
+                .await
+                .map_err(to_status)?;
+        }
+
+        let updates = ReceiverStream::new(rx).map(|(id, value)| {
+            Ok(proto::Point {
+                id: id.get_string(),
+                value: Some(to_proto(&value)),
+            })
+        });
+
+        Ok(Response::new(Box::pin(updates)))
+    }
+}
+
+fn to_status(rejection: Rejection) -> Status {
+    match rejection {
+        Rejection::Unauthenticated(m) => Status::unauthenticated(m),
+        Rejection::Forbidden(m) => Status::permission_denied(m),
+        Rejection::NotFound(m) => Status::not_found(m),
+        Rejection::Invalid(m) => Status::invalid_argument(m),
+        Rejection::Unavailable(m) => Status::unavailable(m),
+    }
+}
+
+fn to_point(point: PointValue) -> proto::Point {
+    proto::Point {
+        id: point.id,
+        value: point.value.as_ref().map(to_proto),
+    }
+}
+
+fn to_proto(value: &Value) -> proto::Value {
+    let kind = match value {
+        Value::Boolean(v) => Kind::Boolean(*v),
+        Value::Blob(v) => Kind::Blob(v.clone()),
+        Value::String(v) => Kind::String(v.clone()),
+        Value::U8(v) => Kind::U8(*v as u32),
+        Value::I8(v) => Kind::I8(*v as i32),
+        Value::U16(v) => Kind::U16(*v as u32),
+        Value::I16(v) => Kind::I16(*v as i32),
+        Value::U32(v) => Kind::U32(*v),
+        Value::I32(v) => Kind::I32(*v),
+        Value::U64(v) => Kind::U64(*v),
+        Value::I64(v) => Kind::I64(*v),
+        Value::F32(v) => Kind::F32(*v),
+        Value::F64(v) => Kind::F64(*v),
+    };
+
+    proto::Value { value: Some(kind) }
+}
+
+fn from_proto(value: proto::Value) -> Result<Value, Status> {
+    let out_of_range = |_| Status::invalid_argument("Value out of range.");
+
+    let value = match value.value {
+        Some(Kind::Boolean(v)) => Value::Boolean(v),
+        Some(Kind::Blob(v)) => Value::Blob(v),
+        Some(Kind::String(v)) => Value::String(v),
+        Some(Kind::U8(v)) => Value::U8(u8::try_from(v).map_err(out_of_range)?),
+        Some(Kind::I8(v)) => Value::I8(i8::try_from(v).map_err(out_of_range)?),
+        Some(Kind::U16(v)) => Value::U16(u16::try_from(v).map_err(out_of_range)?),
+        Some(Kind::I16(v)) => Value::I16(i16::try_from(v).map_err(out_of_range)?),
+        Some(Kind::U32(v)) => Value::U32(v),
+        Some(Kind::I32(v)) => Value::I32(v),
+        Some(Kind::U64(v)) => Value::U64(v),
+        Some(Kind::I64(v)) => Value::I64(v),
+        Some(Kind::F32(v)) => Value::F32(v),
+        Some(Kind::F64(v)) => Value::F64(v),
+        None => return Err(Status::invalid_argument("Missing value.")),
+    };
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_roundtrip() {
+        let values = vec![
+            Value::Boolean(true),
+            Value::Blob(vec![0xde, 0xad]),
+            Value::U8(u8::MAX),
+            Value::I16(i16::MIN),
+            Value::U64(u64::MAX),
+            Value::F32(0.5),
+        ];
+
+        for value in values {
+            assert_eq!(from_proto(to_proto(&value)).unwrap(), value);
+        }
+
+        let too_large = proto::Value {
+            value: Some(Kind::U8(256)),
+        };
+        assert!(from_proto(too_large).is_err());
+    }
+
+    #[tokio::test]
+    async fn stops_listening_with_the_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let gateway = Gateway::new(
+            tx,
+            Arc::new(None),
+            Arc::new(None),
+            crate::config::Limits::default(),
+        );
+
+        let server = tokio::spawn(serve(listener, None, gateway));
+        tokio::net::TcpStream::connect(address).await.unwrap();
+
+        server.abort();
+        let _ = server.await;
+
+        // The accept-loop notices on its own, without another client to accept.
+        let refused = async {
+            while tokio::net::TcpStream::connect(address).await.is_ok() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), refused)
+            .await
+            .unwrap();
+    }
+}
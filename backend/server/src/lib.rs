@@ -0,0 +1,26 @@
+pub mod acl;
+pub mod auth;
+pub mod config;
+pub mod connection;
+mod event_handlers;
+mod exporter;
+mod gateway;
+mod graphql;
+mod grpc;
+pub mod listener;
+pub mod logging;
+mod monitoring;
+mod mqtt;
+mod outbound;
+mod resp;
+mod rest;
+mod server;
+mod sse;
+mod subscriptions;
+#[cfg(test)]
+mod testing;
+pub mod tls;
+mod values;
+mod websocket;
+
+pub use crate::server::{Server, ServerHandle};
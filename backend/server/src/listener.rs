@@ -0,0 +1,234 @@
+use std::io::Error;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_rustls::TlsAcceptor;
+
+use crate::connection::{Address, Incoming, ReadStream, WriteStream};
+use crate::{tls, websocket};
+
+pub type IncomingTx = UnboundedSender<Result<Incoming, Error>>;
+
+/// Pause after a failed accept, e.g. while the process is out of file-descriptors.
+pub const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+pub enum Listener {
+    /// The native protocol over TCP.
+    Tcp(TcpListener),
+    /// The native protocol in binary websocket-frames, for browsers.
+    WebSocket(TcpListener),
+    /// The JSON gateway, see `rest::serve`.
+    Http(TcpListener),
+    /// MQTT 3.1.1 and 5, see `mqtt::accept`.
+    Mqtt(TcpListener),
+    /// Redis-clients, see `resp::accept`.
+    Resp(TcpListener),
+    /// The gRPC-service, see `grpc::serve`.
+    Grpc(TcpListener),
+    /// Values of the points matching the glob for Prometheus, see `exporter::serve`.
+    Exporter(TcpListener, String),
+    /// The native protocol for producers on the same host. Access is controlled by the
+    /// permissions of the socket-file, so there is no TLS.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// Accepts connections until the server stops listening. Handshakes run in their own tasks so a
+/// slow client can't hold up the accept-loop.
+pub async fn accept(
+    listener: TcpListener,
+    websocket: bool,
+    tls: Option<TlsAcceptor>,
+    timeout: Duration,
+    tx: IncomingTx,
+) {
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                if tx.send(Err(e)).is_err() {
+                    break;
+                }
+
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+
+        // Writes are already batched per connection, Nagle's algorithm would only hold back
+        // the last packet of a batch until the client acknowledges the previous one.
+        let _ = stream.set_nodelay(true);
+
+        if !websocket && tls.is_none() {
+            let (read, write) = stream.into_split();
+            let incoming = Incoming {
+                read: Box::new(read),
+                write: Box::new(write),
+                address: Address::Tcp(address),
+                principal: None,
+                framed: false,
+            };
+
+            if tx.send(Ok(incoming)).is_err() {
+                break;
+            }
+
+            continue;
+        }
+
+        let tls = tls.clone();
+        let tx = tx.clone();
+
+        tokio::spawn(async move {
+            let _ = tx.send(handshake(stream, address, tls, timeout, websocket).await);
+        });
+    }
+}
+
+async fn handshake(
+    stream: TcpStream,
+    address: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    timeout: Duration,
+    websocket: bool,
+) -> Result<Incoming, Error> {
+    let (read, write, principal) = match tls {
+        Some(acceptor) => {
+            let (stream, principal) = tls::accept(&acceptor, stream, timeout).await?;
+            let (read, write) = split(stream, websocket).await?;
+
+            (read, write, principal)
+        }
+        None => {
+            let (read, write) = split(stream, websocket).await?;
+
+            (read, write, None)
+        }
+    };
+
+    Ok(Incoming {
+        read,
+        write,
+        address: Address::Tcp(address),
+        principal,
+        framed: websocket,
+    })
+}
+
+/// Binds the socket-file with permissions for its owner and group only. The socket is bound in a
+/// private directory and moved into place, so it is never reachable with the umask's permissions.
+/// A stale socket a previous run left behind is replaced, anything else at `path` is kept.
+#[cfg(unix)]
+pub fn bind_unix(path: &Path) -> Result<UnixListener, Error> {
+    use std::fs::{DirBuilder, Permissions};
+    use std::io::ErrorKind;
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket.", path.display()),
+            ));
+        }
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return Err(Error::new(ErrorKind::InvalidInput, "Invalid socket-path.")),
+    };
+
+    let private = path.with_file_name(format!(".{}.{}", name, std::process::id()));
+    DirBuilder::new().mode(0o700).create(&private)?;
+
+    let staged = private.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, Permissions::from_mode(0o660))?;
+        std::fs::rename(&staged, path)?;
+
+        Ok(listener)
+    });
+
+    let _ = std::fs::remove_dir_all(&private);
+
+    bound
+}
+
+/// Accepts unix-socket connections until the server stops listening.
+#[cfg(unix)]
+pub async fn accept_unix(listener: UnixListener, path: PathBuf, tx: IncomingTx) {
+    loop {
+        let incoming = listener.accept().await.map(|(stream, _)| {
+            let (read, write) = stream.into_split();
+
+            Incoming {
+                read: Box::new(read),
+                write: Box::new(write),
+                address: Address::Unix(path.clone()),
+                principal: None,
+                framed: false,
+            }
+        });
+        let failed = incoming.is_err();
+
+        if tx.send(incoming).is_err() {
+            break;
+        }
+
+        if failed {
+            tokio::time::sleep(ACCEPT_BACKOFF).await;
+        }
+    }
+}
+
+async fn split<S>(stream: S, websocket: bool) -> Result<(ReadStream, WriteStream), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if websocket {
+        return websocket::accept(stream).await;
+    }
+
+    let (read, write) = tokio::io::split(stream);
+
+    Ok((Box::new(read), Box::new(write)))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn binds_socket_for_owner_and_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rmber.sock");
+
+        // The second bind replaces the socket the first one left behind.
+        drop(bind_unix(&path).unwrap());
+        let _listener = bind_unix(&path).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        // Only the socket is left, not the directory it was bound in.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_files_that_are_no_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rmber.toml");
+        std::fs::write(&path, "[store]").unwrap();
+
+        assert!(bind_unix(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "[store]");
+    }
+}
@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind, IsTerminal};
+use std::str::FromStr;
+use tracing_subscriber::EnvFilter;
+
+/// Used when neither `RUST_LOG` nor the configuration sets a filter.
+pub const DEFAULT_FILTER: &str = "info";
+
+/// How log-lines are written to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable, with the fields of the enclosing spans, e.g.
+    /// `INFO connection{id=.. peer=127.0.0.1:5123}: server: Authenticated principal=..`.
+    Text,
+    /// One JSON-object per line, for log-collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown log-format {}, expected text or json.", s),
+            )),
+        }
+    }
+}
+
+/// Installs the global subscriber. `filter` uses the `RUST_LOG`-syntax, e.g.
+/// `info,server=debug` also shows a span for every packet with its type and point.
+pub fn init(filter: &str, format: LogFormat) -> Result<(), Error> {
+    let filter = EnvFilter::try_new(filter).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(std::io::stdout().is_terminal());
+
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    };
+
+    result.map_err(Error::other)
+}
@@ -1,19 +1,328 @@
-use tokio::net::TcpListener;
+use clap::{Parser, ValueEnum};
+use std::io::Error;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+use server::acl::Acl;
+use server::auth::Credentials;
+use server::config::{Config, SlowConsumerPolicy};
+use server::listener::{self, Listener};
+use server::logging::{self, LogFormat};
+use server::tls;
+use server::Server;
+use store::rocksdb::create_rocksdb;
+use tracing::{error, info, warn};
+
+/// Flags override the values of the configuration-file, listen-flags replace all addresses of
+/// their front-end and may be repeated.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// TOML-file with the configuration, see `--print-config` for all keys.
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Print the configuration after applying the flags and exit.
+    #[arg(long)]
+    print_config: bool,
+
+    /// Directory of the database.
+    #[arg(long, value_name = "PATH")]
+    db: Option<PathBuf>,
+
+    /// Address for native connections, e.g. `0.0.0.0:8080` or `[::1]:8080`.
+    #[arg(long = "listen", value_name = "ADDRESS")]
+    native: Vec<SocketAddr>,
+
+    #[arg(long, value_name = "ADDRESS")]
+    websocket: Vec<SocketAddr>,
+
+    /// Address for REST, SSE, GraphQL and the server's metrics.
+    #[arg(long, value_name = "ADDRESS")]
+    http: Vec<SocketAddr>,
+
+    /// Address for MQTT, which is off without one.
+    #[arg(long, value_name = "ADDRESS")]
+    mqtt: Vec<SocketAddr>,
+
+    /// Address for Redis-clients, which is off without one.
+    #[arg(long, value_name = "ADDRESS")]
+    resp: Vec<SocketAddr>,
+
+    #[arg(long, value_name = "ADDRESS")]
+    grpc: Vec<SocketAddr>,
+
+    #[arg(long, value_name = "ADDRESS")]
+    exporter: Vec<SocketAddr>,
+
+    /// Path of a unix-socket for native connections.
+    #[arg(long, value_name = "PATH")]
+    unix: Vec<PathBuf>,
+
+    /// Glob over the points to export as Prometheus-gauges, enables the exporter.
+    #[arg(long, value_name = "GLOB")]
+    export_points: Option<String>,
+
+    /// Front-end to turn off.
+    #[arg(long, value_enum, value_name = "FRONTEND")]
+    disable: Vec<Frontend>,
+
+    /// Native connections beyond this are closed, other front-ends aren't limited
+    #[arg(long, value_name = "COUNT")]
+    max_native_connections: Option<usize>,
+
+    #[arg(long, value_name = "SECONDS")]
+    auth_timeout: Option<u64>,
+
+    /// Updates queued per connection or subscription.
+    #[arg(long, value_name = "COUNT")]
+    outbound_queue: Option<usize>,
+
+    /// `disconnect`, `drop_oldest` or `coalesce`, for connections whose queue is full.
+    #[arg(long, value_name = "POLICY")]
+    slow_consumer: Option<SlowConsumerPolicy>,
+
+    /// Microseconds a connection waits for more updates to write at once, 0 disables batching.
+    #[arg(long, value_name = "MICROSECONDS")]
+    flush_interval: Option<u64>,
+
+    /// Time to notify clients, drain updates and flush the store on SIGINT or SIGTERM.
+    #[arg(long, value_name = "SECONDS")]
+    shutdown_timeout: Option<u64>,
+
+    /// Log-filter like `RUST_LOG`, e.g. `info,server=debug`.
+    #[arg(long, value_name = "FILTER")]
+    log: Option<String>,
+
+    /// `text` or `json`.
+    #[arg(long, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Frontend {
+    Native,
+    Websocket,
+    Http,
+    Mqtt,
+    Resp,
+    Grpc,
+    Exporter,
+    Unix,
+}
+
+/// Defaults, then the file, then `RUST_LOG`, then the flags.
+fn configure(cli: Cli) -> Result<Config, Error> {
+    let mut config = match &cli.config {
+        Some(path) => Config::load(path).map_err(|e| could_not_load(path, e))?,
+        None => Config::default(),
+    };
+
+    let listen = &mut config.listen;
+    let addresses = [
+        (&mut listen.native, cli.native),
+        (&mut listen.websocket, cli.websocket),
+        (&mut listen.http, cli.http),
+        (&mut listen.mqtt, cli.mqtt),
+        (&mut listen.resp, cli.resp),
+        (&mut listen.grpc, cli.grpc),
+        (&mut listen.exporter, cli.exporter),
+    ];
+
+    for (configured, flags) in addresses {
+        if !flags.is_empty() {
+            *configured = flags;
+        }
+    }
+
+    if !cli.unix.is_empty() {
+        listen.unix = cli.unix;
+    }
+
+    for frontend in cli.disable {
+        match frontend {
+            Frontend::Native => listen.native.clear(),
+            Frontend::Websocket => listen.websocket.clear(),
+            Frontend::Http => listen.http.clear(),
+            Frontend::Mqtt => listen.mqtt.clear(),
+            Frontend::Resp => listen.resp.clear(),
+            Frontend::Grpc => listen.grpc.clear(),
+            Frontend::Exporter => listen.exporter.clear(),
+            Frontend::Unix => listen.unix.clear(),
+        }
+    }
+
+    if let Some(db) = cli.db {
+        config.store.path = db;
+    }
 
-mod server;
-mod connection;
-mod event_handlers;
+    if let Some(points) = cli.export_points {
+        config.exporter.points = Some(points);
+    }
 
-use server::*;
+    if let Some(max) = cli.max_native_connections {
+        config.limits.max_native_connections = Some(max);
+    }
+
+    if let Some(seconds) = cli.auth_timeout {
+        config.limits.auth_timeout = Duration::from_secs(seconds);
+    }
+
+    if let Some(count) = cli.outbound_queue {
+        config.limits.outbound_queue = count;
+    }
+
+    if let Some(policy) = cli.slow_consumer {
+        config.limits.slow_consumer = policy;
+    }
+
+    if let Some(micros) = cli.flush_interval {
+        config.limits.flush_interval = Duration::from_micros(micros);
+    }
+
+    if let Some(seconds) = cli.shutdown_timeout {
+        config.limits.shutdown_timeout = Duration::from_secs(seconds);
+    }
+
+    match (cli.log, std::env::var("RUST_LOG")) {
+        (Some(filter), _) | (None, Ok(filter)) => config.log.filter = filter,
+        (None, Err(_)) => {}
+    }
+
+    if let Some(format) = cli.log_format {
+        config.log.format = format;
+    }
+
+    config.validate()?;
+
+    Ok(config)
+}
+
+fn could_not_load(path: &Path, e: Error) -> Error {
+    Error::new(
+        e.kind(),
+        format!("Could not load {}: {}", path.display(), e),
+    )
+}
+
+async fn bind(address: &SocketAddr) -> Result<TcpListener, Error> {
+    TcpListener::bind(address)
+        .await
+        .map_err(|e| Error::new(e.kind(), format!("Could not listen on {}: {}", address, e)))
+}
+
+async fn bind_all(config: &Config) -> Result<Vec<Listener>, Error> {
+    let listen = &config.listen;
+    let mut listeners = vec![];
+
+    for address in &listen.native {
+        listeners.push(Listener::Tcp(bind(address).await?));
+    }
+
+    for address in &listen.websocket {
+        listeners.push(Listener::WebSocket(bind(address).await?));
+    }
+
+    for address in &listen.http {
+        listeners.push(Listener::Http(bind(address).await?));
+    }
+
+    for address in &listen.mqtt {
+        listeners.push(Listener::Mqtt(bind(address).await?));
+    }
+
+    for address in &listen.resp {
+        listeners.push(Listener::Resp(bind(address).await?));
+    }
+
+    for address in &listen.grpc {
+        listeners.push(Listener::Grpc(bind(address).await?));
+    }
+
+    if let Some(points) = &config.exporter.points {
+        for address in config.exporter_addresses() {
+            listeners.push(Listener::Exporter(bind(address).await?, points.clone()));
+        }
+    }
+
+    #[cfg(unix)]
+    for path in &listen.unix {
+        listeners.push(Listener::Unix(listener::bind_unix(path)?, path.clone()));
+    }
+
+    Ok(listeners)
+}
 
 #[tokio::main]
 pub async fn main() -> std::io::Result<()> {
-    let address = SocketAddr::new("127.0.0.1".parse().unwrap(), 8080);
-    let listener = TcpListener::bind(address).await?;
-    let mut server = Server::new(listener);
+    let cli = Cli::parse();
+    let print_config = cli.print_config;
+    let config = configure(cli)?;
+
+    if print_config {
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
+
+    logging::init(&config.log.filter, config.log.format)?;
+
+    let listeners = bind_all(&config).await?;
+    let security = &config.security;
+
+    // Configured files are required, a typo must not start the server without them.
+    let credentials = match &security.credentials {
+        Some(path) => Some(Credentials::load(path).map_err(|e| could_not_load(path, e))?),
+        None => {
+            warn!("No credentials configured, every client is accepted as anonymous");
+
+            // Older versions read it without being configured, don't let an upgrade open the server
+            // silently.
+            if Path::new("./credentials.toml").exists() {
+                warn!("./credentials.toml is ignored, configure it as security.credentials");
+            }
+
+            None
+        }
+    };
+
+    let acl = match &security.acl {
+        Some(path) => Some(Acl::load(path).map_err(|e| could_not_load(path, e))?),
+        None => {
+            info!("No acl configured, access control is disabled");
+            None
+        }
+    };
+
+    let tls = match (&security.tls_cert, &security.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::load_acceptor(
+            cert,
+            key,
+            security.tls_client_ca.as_deref(),
+        )?),
+        _ => {
+            info!("No certificate configured, TLS is disabled");
+            None
+        }
+    };
 
-    server.run().await;
+    let store = create_rocksdb(&config.store.path).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("Could not open {}: {}", config.store.path.display(), e),
+        )
+    })?;
+    let mut server = Server::new(store, listeners, tls, credentials, acl, config.limits);
 
-    Ok(())
+    match server.run().await {
+        Ok(_) => {
+            info!("Shut down");
+            Ok(())
+        }
+        Err(e) => {
+            error!(error = %e, "Shutdown was incomplete");
+            std::process::exit(1);
+        }
+    }
 }
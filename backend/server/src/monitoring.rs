@@ -0,0 +1,100 @@
+use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Buckets for the histograms measuring latencies in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0,
+];
+
+/// Buckets for `rmber_store_batch_writes`, which counts writes up to the store's batch-limit.
+const BATCH_BUCKETS: [f64; 11] = [
+    1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0, 512.0, 1024.0,
+];
+
+/// How often histograms are compacted while nobody scrapes them.
+pub const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Installs the recorder on first use, so all servers of a process share it. An application
+/// embedding the server may have installed its own recorder, then `/metrics` stays empty.
+pub fn install() -> &'static PrometheusHandle {
+    HANDLE.get_or_init(|| {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets(&LATENCY_BUCKETS)
+            .expect("Buckets are not empty.")
+            .set_buckets_for_metric(
+                Matcher::Full(String::from("rmber_store_batch_writes")),
+                &BATCH_BUCKETS,
+            )
+            .expect("Buckets are not empty.")
+            .build_recorder();
+
+        let handle = recorder.handle();
+
+        if metrics::set_global_recorder(recorder).is_ok() {
+            describe();
+        }
+
+        handle
+    })
+}
+
+/// The text-format Prometheus scrapes.
+pub fn render() -> String {
+    install().render()
+}
+
+fn describe() {
+    describe_gauge!(
+        "rmber_connections",
+        "Connections speaking the native protocol."
+    );
+    describe_gauge!(
+        "rmber_streams",
+        "Subscriptions of HTTP, MQTT, RESP, gRPC and GraphQL clients."
+    );
+    describe_counter!("rmber_packets_total", "Packets received, by type.");
+    describe_histogram!(
+        "rmber_fanout_seconds",
+        Unit::Seconds,
+        "Time from the fan-out of an update until a subscriber has it."
+    );
+    describe_counter!(
+        "rmber_dropped_updates_total",
+        "Updates not delivered to a subscriber, by reason: coalesced, queue_full, closed or write_failed."
+    );
+    describe_counter!(
+        "rmber_slow_consumer_disconnects_total",
+        "Connections closed because their outbound queue was full."
+    );
+    describe_histogram!(
+        "rmber_store_write_seconds",
+        Unit::Seconds,
+        "Time to write a value to the store."
+    );
+    describe_histogram!(
+        "rmber_store_batch_writes",
+        "Writes the store committed together in one batch."
+    );
+    describe_counter!("rmber_schema_rebuilds_total", "Schema rebuilds, by result.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics::histogram;
+
+    #[test]
+    fn batches_have_buckets_of_their_own() {
+        install();
+        histogram!("rmber_store_batch_writes").record(3.0);
+
+        let rendered = render();
+
+        assert!(rendered.contains("rmber_store_batch_writes_bucket{le=\"4\"}"));
+        assert!(!rendered.contains("rmber_store_batch_writes_bucket{le=\"0.005\"}"));
+    }
+}
@@ -0,0 +1,750 @@
+use bytes::{Bytes, BytesMut};
+use mqttbytes::{v4, v5, QoS};
+use schema::NS_DIVIDER;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf};
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+
+use crate::auth::Principal;
+use crate::gateway::{
+    escape_pattern, next_stream_id, Action, Gateway, Input, Rejection, Reply, Session,
+};
+use crate::listener::ACCEPT_BACKOFF;
+use crate::tls;
+use crate::values;
+
+/// Largest packet a client may send.
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    /// MQTT 3.1.1
+    V4,
+    V5,
+}
+
+/// The parts of both protocol-versions the server understands.
+#[derive(Debug)]
+enum Packet {
+    Connect {
+        keep_alive: u16,
+        login: Option<(String, String)>,
+    },
+    Publish {
+        topic: String,
+        qos: QoS,
+        pkid: u16,
+        payload: Bytes,
+    },
+    PubRel {
+        pkid: u16,
+    },
+    Subscribe {
+        pkid: u16,
+        filters: Vec<String>,
+    },
+    Unsubscribe {
+        pkid: u16,
+        topics: Vec<String>,
+    },
+    PingReq,
+    Disconnect,
+    /// Packets only a client should receive, and acks for QoS 2, which the server never sends.
+    Other,
+}
+
+impl From<v4::Packet> for Packet {
+    fn from(packet: v4::Packet) -> Self {
+        match packet {
+            v4::Packet::Connect(c) => Packet::Connect {
+                keep_alive: c.keep_alive,
+                login: c.login.map(|l| (l.username, l.password)),
+            },
+            v4::Packet::Publish(p) => Packet::Publish {
+                topic: p.topic,
+                qos: p.qos,
+                pkid: p.pkid,
+                payload: p.payload,
+            },
+            v4::Packet::PubRel(p) => Packet::PubRel { pkid: p.pkid },
+            v4::Packet::Subscribe(s) => Packet::Subscribe {
+                pkid: s.pkid,
+                filters: s.filters.into_iter().map(|f| f.path).collect(),
+            },
+            v4::Packet::Unsubscribe(u) => Packet::Unsubscribe {
+                pkid: u.pkid,
+                topics: u.topics,
+            },
+            v4::Packet::PingReq => Packet::PingReq,
+            v4::Packet::Disconnect => Packet::Disconnect,
+            _ => Packet::Other,
+        }
+    }
+}
+
+impl From<v5::Packet> for Packet {
+    fn from(packet: v5::Packet) -> Self {
+        match packet {
+            v5::Packet::Connect(c) => Packet::Connect {
+                keep_alive: c.keep_alive,
+                login: c.login.map(|l| (l.username, l.password)),
+            },
+            v5::Packet::Publish(p) => Packet::Publish {
+                topic: p.topic,
+                qos: p.qos,
+                pkid: p.pkid,
+                payload: p.payload,
+            },
+            v5::Packet::PubRel(p) => Packet::PubRel { pkid: p.pkid },
+            v5::Packet::Subscribe(s) => Packet::Subscribe {
+                pkid: s.pkid,
+                filters: s.filters.into_iter().map(|f| f.path).collect(),
+            },
+            v5::Packet::Unsubscribe(u) => Packet::Unsubscribe {
+                pkid: u.pkid,
+                topics: u.filters,
+            },
+            v5::Packet::PingReq => Packet::PingReq,
+            v5::Packet::Disconnect(_) => Packet::Disconnect,
+            _ => Packet::Other,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Outgoing {
+    ConnAck(Result<(), Rejection>),
+    /// MQTT 3.1.1 has no negative acks, so the rejection only reaches MQTT 5 clients.
+    PubAck(u16, Option<Rejection>),
+    PubRec(u16, Option<Rejection>),
+    PubComp(u16),
+    SubAck(u16, Vec<bool>),
+    UnsubAck(u16, usize),
+    PingResp,
+    /// Everything is sent with QoS 0, values are superseded by the next update anyway.
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        retain: bool,
+    },
+}
+
+/// Accepts MQTT-clients until the server stops listening. With TLS a client-certificate
+/// authenticates the client like on the native listener, otherwise the CONNECT-login does.
+pub async fn accept(listener: TcpListener, tls: Option<TlsAcceptor>, gateway: Gateway) {
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(error = %e, "Could not accept MQTT-client");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let tls = tls.clone();
+        let timeout = gateway.limits().auth_timeout;
+        let gateway = gateway.clone();
+
+        let span = info_span!("mqtt", peer = %address, principal = field::Empty);
+
+        tokio::spawn(
+            async move {
+                debug!("New MQTT-client");
+
+                let result = match tls {
+                    Some(acceptor) => match tls::accept(&acceptor, stream, timeout).await {
+                        Ok((stream, principal)) => session(stream, principal, gateway).await,
+                        Err(e) => Err(e),
+                    },
+                    None => session(stream, None, gateway).await,
+                };
+
+                match result {
+                    Ok(_) => debug!("MQTT-client disconnected"),
+                    Err(e) => info!(reason = %e, "MQTT-client disconnected"),
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+enum Event {
+    Packet(Packet),
+    Update(String, Vec<u8>),
+}
+
+async fn session<S>(stream: S, principal: Option<Principal>, gateway: Gateway) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut read, mut write) = tokio::io::split(stream);
+    let mut buffer = BytesMut::with_capacity(4096);
+    let deadline = Instant::now() + gateway.limits().auth_timeout;
+
+    // The protocol-level of CONNECT decides how everything after it is read.
+    let version = loop {
+        match protocol_level(&buffer)? {
+            Some(5) => break Version::V5,
+            Some(_) => break Version::V4,
+            None if buffer.len() >= MAX_PACKET_SIZE => {
+                return Err(Error::new(ErrorKind::InvalidData, "CONNECT is too large."))
+            }
+            None => read_more(&mut read, &mut buffer, deadline).await?,
+        }
+    };
+
+    let (keep_alive, login) = match next_packet(&mut read, &mut buffer, version, deadline).await? {
+        Packet::Connect { keep_alive, login } => (keep_alive, login),
+        _ => return Err(Error::new(ErrorKind::InvalidData, "Expected CONNECT.")),
+    };
+
+    // A principal from the transport, e.g. a client-certificate, wins over the login.
+    let session = match (principal, login) {
+        (Some(principal), _) => gateway.authenticate_principal(Some(principal)),
+        (None, Some((user, password))) => gateway.authenticate(&user, &password).await,
+        (None, None) => gateway.authenticate_principal(None),
+    };
+
+    let session = match session {
+        Ok(session) => {
+            Span::current().record("principal", session.principal.name.as_str());
+            session
+        }
+        Err(rejection) => {
+            send(
+                &mut write,
+                version,
+                Outgoing::ConnAck(Err(rejection.clone())),
+            )
+            .await?;
+            return Err(Error::new(ErrorKind::PermissionDenied, rejection.message()));
+        }
+    };
+
+    send(&mut write, version, Outgoing::ConnAck(Ok(()))).await?;
+
+    // Clients have to send something within one and a half keep-alive intervals.
+    let keep_alive = Duration::from_millis(keep_alive as u64 * 1500);
+    let idle_deadline = || match keep_alive.as_millis() {
+        0 => Instant::now() + Duration::from_secs(60 * 60 * 24 * 365),
+        _ => Instant::now() + keep_alive,
+    };
+
+    let stream = next_stream_id();
+    let (update_tx, mut update_rx) = gateway.update_channel();
+    let mut deadline = idle_deadline();
+
+    loop {
+        let event = tokio::select! {
+            packet = next_packet(&mut read, &mut buffer, version, deadline) => Event::Packet(packet?),
+            Some((id, value)) = update_rx.recv() => Event::Update(id.get_string(), values::to_text(&value)),
+        };
+
+        let packet = match event {
+            Event::Packet(packet) => packet,
+            Event::Update(topic, payload) => {
+                let publish = Outgoing::Publish {
+                    topic,
+                    payload,
+                    retain: false,
+                };
+                send(&mut write, version, publish).await?;
+                continue;
+            }
+        };
+
+        deadline = idle_deadline();
+
+        match packet {
+            Packet::Publish {
+                topic,
+                qos,
+                pkid,
+                payload,
+            } => {
+                let action = Action::Update(topic.clone(), Input::Text(payload.to_vec()));
+                let rejection = gateway.request(&session, action).await.err();
+
+                if let Some(rejection) = &rejection {
+                    debug!(key = %topic, reason = rejection.message(), "Rejected publish");
+                }
+
+                match qos {
+                    QoS::AtMostOnce => {}
+                    QoS::AtLeastOnce => {
+                        send(&mut write, version, Outgoing::PubAck(pkid, rejection)).await?
+                    }
+                    QoS::ExactlyOnce => {
+                        send(&mut write, version, Outgoing::PubRec(pkid, rejection)).await?
+                    }
+                }
+            }
+            Packet::PubRel { pkid } => send(&mut write, version, Outgoing::PubComp(pkid)).await?,
+            Packet::Subscribe { pkid, filters } => {
+                let mut granted = vec![];
+                let mut patterns = vec![];
+
+                for filter in filters {
+                    let pattern = match filter_to_glob(&filter) {
+                        Some(pattern) => pattern,
+                        None => {
+                            granted.push(false);
+                            continue;
+                        }
+                    };
+
+                    let subscribe = Action::Subscribe {
+                        stream,
+                        pattern: pattern.clone(),
+                        tx: update_tx.clone(),
+                    };
+
+                    let ok = gateway.request(&session, subscribe).await.is_ok();
+                    if ok {
+                        patterns.push(pattern);
+                    }
+
+                    granted.push(ok);
+                }
+
+                send(&mut write, version, Outgoing::SubAck(pkid, granted)).await?;
+                send_retained(&mut write, version, &gateway, &session, patterns).await?;
+            }
+            Packet::Unsubscribe { pkid, topics } => {
+                let count = topics.len();
+
+                for topic in topics {
+                    if let Some(pattern) = filter_to_glob(&topic) {
+                        let unsubscribe = Action::Unsubscribe { stream, pattern };
+                        let _ = gateway.request(&session, unsubscribe).await;
+                    }
+                }
+
+                send(&mut write, version, Outgoing::UnsubAck(pkid, count)).await?;
+            }
+            Packet::PingReq => send(&mut write, version, Outgoing::PingResp).await?,
+            Packet::Disconnect => return Ok(()),
+            Packet::Connect { .. } => {
+                return Err(Error::new(ErrorKind::InvalidData, "Second CONNECT."))
+            }
+            Packet::Other => {}
+        }
+    }
+}
+
+/// The current values of newly subscribed points, like retained messages of a broker.
+async fn send_retained<W>(
+    write: &mut W,
+    version: Version,
+    gateway: &Gateway,
+    session: &Session,
+    patterns: Vec<String>,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    for pattern in patterns {
+        let points = match gateway.request(session, Action::Query(pattern)).await {
+            Ok(Reply::Points(points)) => points,
+            _ => continue,
+        };
+
+        for point in points {
+            if let Some(value) = point.value {
+                let publish = Outgoing::Publish {
+                    topic: point.id,
+                    payload: values::to_text(&value),
+                    retain: true,
+                };
+
+                send(write, version, publish).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// `+` matches one level like `*` and `#` any number of levels like `**`, everything else is
+/// matched literally.
+fn filter_to_glob(filter: &str) -> Option<String> {
+    if !mqttbytes::valid_filter(filter) {
+        return None;
+    }
+
+    let levels: Vec<String> = filter
+        .split('/')
+        .map(|level| match level {
+            "+" => String::from("*"),
+            "#" => String::from("**"),
+            literal => escape_pattern(literal),
+        })
+        .collect();
+
+    Some(levels.join(NS_DIVIDER))
+}
+
+/// Reads the protocol-level of a CONNECT at the start of the buffer, if there are enough bytes.
+fn protocol_level(buffer: &[u8]) -> Result<Option<u8>, Error> {
+    // Fixed header: packet-type and a remaining length of up to four bytes.
+    let mut index = 1;
+
+    loop {
+        match buffer.get(index) {
+            None => return Ok(None),
+            Some(byte) if byte & 0x80 == 0 => break,
+            Some(_) if index == 4 => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Malformed remaining length.",
+                ))
+            }
+            Some(_) => index += 1,
+        }
+    }
+
+    // Variable header: protocol-name and level.
+    let name = match (buffer.get(index + 1), buffer.get(index + 2)) {
+        (Some(&high), Some(&low)) => u16::from_be_bytes([high, low]) as usize,
+        _ => return Ok(None),
+    };
+
+    Ok(buffer.get(index + 3 + name).copied())
+}
+
+async fn read_more<R>(read: &mut R, buffer: &mut BytesMut, deadline: Instant) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let read = tokio::time::timeout_at(deadline, read.read_buf(buffer));
+
+    match read.await {
+        Ok(Ok(0)) => Err(Error::new(ErrorKind::UnexpectedEof, "Client hung up.")),
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "Client is idle.")),
+    }
+}
+
+async fn next_packet<S>(
+    read: &mut ReadHalf<S>,
+    buffer: &mut BytesMut,
+    version: Version,
+    deadline: Instant,
+) -> Result<Packet, Error>
+where
+    S: AsyncRead,
+{
+    loop {
+        let packet = match version {
+            Version::V4 => v4::read(buffer, MAX_PACKET_SIZE).map(Packet::from),
+            Version::V5 => v5::read(buffer, MAX_PACKET_SIZE).map(Packet::from),
+        };
+
+        match packet {
+            Ok(packet) => return Ok(packet),
+            Err(mqttbytes::Error::InsufficientBytes(_)) => {
+                read_more(read, buffer, deadline).await?
+            }
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, format!("{:?}", e))),
+        }
+    }
+}
+
+async fn send<W>(write: &mut W, version: Version, packet: Outgoing) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = BytesMut::new();
+
+    let result = match version {
+        Version::V4 => encode_v4(packet, &mut buffer),
+        Version::V5 => encode_v5(packet, &mut buffer),
+    };
+
+    if let Err(e) = result {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{:?}", e)));
+    }
+
+    write.write_all(&buffer).await?;
+    write.flush().await
+}
+
+fn encode_v4(packet: Outgoing, buffer: &mut BytesMut) -> Result<usize, mqttbytes::Error> {
+    match packet {
+        Outgoing::ConnAck(result) => {
+            let code = match result {
+                Ok(_) => v4::ConnectReturnCode::Success,
+                Err(Rejection::Unauthenticated(_)) => v4::ConnectReturnCode::BadUserNamePassword,
+                Err(_) => v4::ConnectReturnCode::NotAuthorized,
+            };
+
+            v4::ConnAck::new(code, false).write(buffer)
+        }
+        Outgoing::PubAck(pkid, _) => v4::PubAck::new(pkid).write(buffer),
+        Outgoing::PubRec(pkid, _) => v4::PubRec::new(pkid).write(buffer),
+        Outgoing::PubComp(pkid) => v4::PubComp::new(pkid).write(buffer),
+        Outgoing::SubAck(pkid, granted) => {
+            let codes = granted
+                .into_iter()
+                .map(|ok| match ok {
+                    true => v4::SubscribeReasonCode::Success(QoS::AtMostOnce),
+                    false => v4::SubscribeReasonCode::Failure,
+                })
+                .collect();
+
+            v4::SubAck::new(pkid, codes).write(buffer)
+        }
+        Outgoing::UnsubAck(pkid, _) => v4::UnsubAck::new(pkid).write(buffer),
+        Outgoing::PingResp => v4::PingResp.write(buffer),
+        Outgoing::Publish {
+            topic,
+            payload,
+            retain,
+        } => {
+            let mut publish = v4::Publish::new(topic, QoS::AtMostOnce, payload);
+            publish.retain = retain;
+
+            publish.write(buffer)
+        }
+    }
+}
+
+fn encode_v5(packet: Outgoing, buffer: &mut BytesMut) -> Result<usize, mqttbytes::Error> {
+    match packet {
+        Outgoing::ConnAck(result) => {
+            let code = match result {
+                Ok(_) => v5::ConnectReturnCode::Success,
+                Err(Rejection::Unauthenticated(_)) => v5::ConnectReturnCode::BadUserNamePassword,
+                Err(_) => v5::ConnectReturnCode::NotAuthorized,
+            };
+
+            v5::ConnAck::new(code, false).write(buffer)
+        }
+        Outgoing::PubAck(pkid, rejection) => {
+            let mut ack = v5::PubAck::new(pkid);
+            ack.reason = match rejection {
+                None => v5::PubAckReason::Success,
+                Some(Rejection::Unauthenticated(_) | Rejection::Forbidden(_)) => {
+                    v5::PubAckReason::NotAuthorized
+                }
+                Some(Rejection::NotFound(_)) => v5::PubAckReason::TopicNameInvalid,
+                Some(Rejection::Invalid(_)) => v5::PubAckReason::PayloadFormatInvalid,
+                Some(Rejection::Unavailable(_)) => v5::PubAckReason::UnspecifiedError,
+            };
+
+            ack.write(buffer)
+        }
+        Outgoing::PubRec(pkid, rejection) => {
+            let mut rec = v5::PubRec::new(pkid);
+            rec.reason = match rejection {
+                None => v5::PubRecReason::Success,
+                Some(Rejection::Unauthenticated(_) | Rejection::Forbidden(_)) => {
+                    v5::PubRecReason::NotAuthorized
+                }
+                Some(Rejection::NotFound(_)) => v5::PubRecReason::TopicNameInvalid,
+                Some(Rejection::Invalid(_)) => v5::PubRecReason::PayloadFormatInvalid,
+                Some(Rejection::Unavailable(_)) => v5::PubRecReason::UnspecifiedError,
+            };
+
+            rec.write(buffer)
+        }
+        Outgoing::PubComp(pkid) => v5::PubComp::new(pkid).write(buffer),
+        Outgoing::SubAck(pkid, granted) => {
+            let codes = granted
+                .into_iter()
+                .map(|ok| match ok {
+                    true => v5::SubscribeReasonCode::QoS0,
+                    false => v5::SubscribeReasonCode::NotAuthorized,
+                })
+                .collect();
+
+            v5::SubAck::new(pkid, codes).write(buffer)
+        }
+        Outgoing::UnsubAck(pkid, count) => {
+            let mut ack = v5::UnsubAck::new(pkid);
+            ack.reasons = vec![v5::UnsubAckReason::Success; count];
+
+            ack.write(buffer)
+        }
+        Outgoing::PingResp => v5::PingResp.write(buffer),
+        Outgoing::Publish {
+            topic,
+            payload,
+            retain,
+        } => {
+            let mut publish = v5::Publish::new(topic, QoS::AtMostOnce, payload);
+            publish.retain = retain;
+
+            publish.write(buffer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::Listener;
+    use crate::testing::{producer, request, secured, spawn_server, update, PCT};
+    use mqttbytes::v4::{ConnectReturnCode, SubscribeReasonCode};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::net::TcpStream;
+
+    struct Client {
+        stream: TcpStream,
+        buffer: BytesMut,
+    }
+
+    impl Client {
+        async fn connect(address: SocketAddr, token: &str) -> (Self, ConnectReturnCode) {
+            let mut client = Client {
+                stream: TcpStream::connect(address).await.unwrap(),
+                buffer: BytesMut::new(),
+            };
+
+            let mut connect = v4::Connect::new("dashboard");
+            connect.set_login("", token);
+            client.send(|buffer| connect.write(buffer)).await;
+
+            match client.next().await {
+                v4::Packet::ConnAck(ack) => (client, ack.code),
+                packet => panic!("Unexpected packet {:?}", packet),
+            }
+        }
+
+        async fn send<F>(&mut self, write: F)
+        where
+            F: FnOnce(&mut BytesMut) -> Result<usize, mqttbytes::Error>,
+        {
+            let mut buffer = BytesMut::new();
+            write(&mut buffer).unwrap();
+
+            self.stream.write_all(&buffer).await.unwrap();
+        }
+
+        async fn next(&mut self) -> v4::Packet {
+            loop {
+                match v4::read(&mut self.buffer, 4096) {
+                    Ok(packet) => return packet,
+                    Err(mqttbytes::Error::InsufficientBytes(_)) => {}
+                    Err(e) => panic!("Invalid packet {:?}", e),
+                }
+
+                let read = self.stream.read_buf(&mut self.buffer).await.unwrap();
+                assert_ne!(read, 0, "Connection closed");
+            }
+        }
+    }
+
+    #[test]
+    fn filters_translate_to_globs() {
+        assert_eq!(filter_to_glob("plant/+/speed").unwrap(), "plant/*/speed");
+        assert_eq!(filter_to_glob("plant/#").unwrap(), "plant/**");
+        assert_eq!(filter_to_glob("#").unwrap(), "**");
+        assert_eq!(filter_to_glob("plant/s*").unwrap(), "plant/s\\*");
+        assert_eq!(filter_to_glob("plant/#/speed"), None);
+    }
+
+    #[test]
+    fn reads_protocol_level() {
+        let mut buffer = BytesMut::new();
+
+        v4::Connect::new("client").write(&mut buffer).unwrap();
+        assert_eq!(protocol_level(&buffer).unwrap(), Some(4));
+        assert_eq!(protocol_level(&buffer[..8]).unwrap(), None);
+
+        buffer.clear();
+        v5::Connect::new("client").write(&mut buffer).unwrap();
+        assert_eq!(protocol_level(&buffer).unwrap(), Some(5));
+
+        // The remaining length takes at most four bytes.
+        assert_eq!(
+            protocol_level(&[0x10, 0xff, 0xff, 0xff, 0x7f]).unwrap(),
+            None
+        );
+        assert!(protocol_level(&[0x10, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_overlong_remaining_lengths() {
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let gateway = Gateway::new(
+            tx,
+            Arc::new(None),
+            Arc::new(None),
+            crate::config::Limits::default(),
+        );
+
+        let (mut client, stream) = tokio::io::duplex(1024);
+        client
+            .write_all(&[0x10, 0xff, 0xff, 0xff, 0xff, 0xff])
+            .await
+            .unwrap();
+
+        let result = session(stream, None, gateway).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn clients_get_updates() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = spawn_server(secured(vec![Listener::Mqtt(listener)]));
+
+        let mut producer = producer(&server.handle).await;
+
+        let (_, code) = Client::connect(address, "wrong-token").await;
+        assert_eq!(code, ConnectReturnCode::BadUserNamePassword);
+
+        let (mut dashboard, code) = Client::connect(address, "dashboard-token").await;
+        assert_eq!(code, ConnectReturnCode::Success);
+
+        assert_eq!(
+            request(&mut producer, update("plant/line1/speed", 1)).await,
+            PCT::Ok {}
+        );
+
+        let mut subscribe = v4::Subscribe::new_many(vec![
+            v4::SubscribeFilter::new(String::from("#"), QoS::AtMostOnce),
+            v4::SubscribeFilter::new(String::from("plant/#"), QoS::AtMostOnce),
+        ]);
+        subscribe.pkid = 1;
+        dashboard.send(|buffer| subscribe.write(buffer)).await;
+
+        match dashboard.next().await {
+            v4::Packet::SubAck(ack) => assert_eq!(
+                ack.return_codes,
+                [
+                    SubscribeReasonCode::Failure,
+                    SubscribeReasonCode::Success(QoS::AtMostOnce)
+                ]
+            ),
+            packet => panic!("Unexpected packet {:?}", packet),
+        }
+
+        // The current value comes first, like a retained message.
+        let mut expected = v4::Publish::new("plant/line1/speed", QoS::AtMostOnce, "1");
+        expected.retain = true;
+        assert_eq!(dashboard.next().await, v4::Packet::Publish(expected));
+
+        // The dashboard may not read the second line, so it only gets the first.
+        assert_eq!(
+            request(&mut producer, update("plant/line2/speed", 1)).await,
+            PCT::Ok {}
+        );
+        assert_eq!(
+            request(&mut producer, update("plant/line1/speed", 2)).await,
+            PCT::Ok {}
+        );
+
+        let expected = v4::Publish::new("plant/line1/speed", QoS::AtMostOnce, "2");
+        assert_eq!(dashboard.next().await, v4::Packet::Publish(expected));
+    }
+}
@@ -0,0 +1,268 @@
+use bytes::Bytes;
+use metrics::counter;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::config::SlowConsumerPolicy;
+
+/// What became of an update handed to a subscriber.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Queued, possibly in place of an older update.
+    Queued,
+    /// Left out because the queue is full.
+    Dropped,
+    /// The queue is full and the subscriber should be disconnected. Refuses further updates.
+    Overflow,
+    /// The subscriber has gone away.
+    Closed,
+}
+
+/// An encoded update waiting to be written, `since` is when its fan-out started. The key and the
+/// packet are shared by all subscribers of the update.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Queued {
+    pub key: Bytes,
+    pub packet: Bytes,
+    pub since: Instant,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queue: VecDeque<Queued>,
+    /// Sequence-number of the front of the queue, counting every update ever taken from it.
+    head: u64,
+    /// Sequence-number of the unsent update of each point, only kept when coalescing.
+    positions: HashMap<Bytes, u64>,
+    /// Number of `Hold`s, nothing is taken while there are any.
+    held: usize,
+    closed: bool,
+}
+
+impl State {
+    fn pop_front(&mut self) -> Option<Queued> {
+        let queued = self.queue.pop_front()?;
+
+        if self.positions.get(&queued.key) == Some(&self.head) {
+            self.positions.remove(&queued.key);
+        }
+
+        self.head += 1;
+
+        Some(queued)
+    }
+
+    /// The unsent update of the point, if it is indexed.
+    fn position_mut(&mut self, key: &Bytes) -> Option<&mut Queued> {
+        let seq = *self.positions.get(key)?;
+
+        self.queue.get_mut((seq - self.head) as usize)
+    }
+}
+
+/// The updates the write-loop of a connection has not sent yet, taken in the order they were
+/// pushed. Writes push them without waiting, so a slow client holds up neither the writers nor
+/// other clients. When coalescing, a newer value takes the place of the unsent one of its point,
+/// so the updates of a point are never reordered and each is queued at most once.
+#[derive(Debug)]
+pub struct Outbound {
+    state: Mutex<State>,
+    ready: Notify,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+}
+
+impl Outbound {
+    pub fn new(capacity: usize, policy: SlowConsumerPolicy) -> Self {
+        Outbound {
+            state: Mutex::new(State::default()),
+            ready: Notify::new(),
+            capacity,
+            policy,
+        }
+    }
+
+    /// `key` identifies the point of the update, `packet` is the encoded `Packet::Update`.
+    pub fn push(&self, key: &Bytes, packet: &Bytes, since: Instant) -> Delivery {
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed {
+            return Delivery::Closed;
+        }
+
+        let coalesce = self.policy == SlowConsumerPolicy::Coalesce;
+
+        if coalesce {
+            if let Some(queued) = state.position_mut(key) {
+                queued.packet = packet.clone();
+                queued.since = since;
+                counter!("rmber_dropped_updates_total", "reason" => "coalesced").increment(1);
+
+                return Delivery::Queued;
+            }
+        }
+
+        if state.queue.len() >= self.capacity {
+            if self.policy == SlowConsumerPolicy::Disconnect {
+                state.closed = true;
+                return Delivery::Overflow;
+            }
+
+            state.pop_front();
+            counter!("rmber_dropped_updates_total", "reason" => "queue_full").increment(1);
+        }
+
+        if coalesce {
+            let seq = state.head + state.queue.len() as u64;
+            state.positions.insert(key.clone(), seq);
+        }
+
+        state.queue.push_back(Queued {
+            key: key.clone(),
+            packet: packet.clone(),
+            since,
+        });
+
+        drop(state);
+        self.ready.notify_one();
+
+        Delivery::Queued
+    }
+
+    /// Waits for the next update, `None` once the queue is closed and empty.
+    pub async fn next(&self) -> Option<Queued> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+
+                if state.held == 0 {
+                    if let Some(queued) = state.pop_front() {
+                        return Some(queued);
+                    }
+                }
+
+                if state.closed && state.queue.is_empty() {
+                    return None;
+                }
+            }
+
+            // Only the write-loop waits, so a permit stored by notify_one is never lost.
+            self.ready.notified().await;
+        }
+    }
+
+    /// Refuses further updates, those already queued are still taken.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_one();
+    }
+
+    /// Updates are still queued, but only taken once the returned guard is dropped. Keeps the
+    /// write-loop from sending an update before the answer to the packet that caused it.
+    pub fn hold(&self) -> Hold<'_> {
+        self.state.lock().unwrap().held += 1;
+
+        Hold(self)
+    }
+}
+
+#[derive(Debug)]
+pub struct Hold<'a>(&'a Outbound);
+
+impl Drop for Hold<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.held -= 1;
+
+        if state.held == 0 {
+            drop(state);
+            self.0.ready.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{encode_update, StringKey, Value};
+
+    fn update(id: &str, value: i32) -> Bytes {
+        encode_update(&StringKey::new(id).unwrap(), &Value::I32(value))
+    }
+
+    fn push(outbound: &Outbound, id: &str, value: i32) -> Delivery {
+        let key = Bytes::copy_from_slice(id.as_bytes());
+
+        outbound.push(&key, &update(id, value), Instant::now())
+    }
+
+    async fn drain(outbound: &Outbound) -> Vec<Bytes> {
+        outbound.close();
+
+        let mut updates = vec![];
+
+        while let Some(queued) = outbound.next().await {
+            updates.push(queued.packet);
+        }
+
+        updates
+    }
+
+    #[tokio::test]
+    async fn policies_apply_when_full() {
+        let outbound = Outbound::new(2, SlowConsumerPolicy::DropOldest);
+        for (id, value) in [("a", 1), ("b", 2), ("a", 3)] {
+            assert_eq!(push(&outbound, id, value), Delivery::Queued);
+        }
+        assert_eq!(drain(&outbound).await, vec![update("b", 2), update("a", 3)]);
+
+        let outbound = Outbound::new(2, SlowConsumerPolicy::Coalesce);
+        for (id, value) in [("a", 1), ("b", 2), ("a", 3), ("c", 4)] {
+            assert_eq!(push(&outbound, id, value), Delivery::Queued);
+        }
+        assert_eq!(drain(&outbound).await, vec![update("b", 2), update("c", 4)]);
+
+        let outbound = Outbound::new(1, SlowConsumerPolicy::Disconnect);
+        assert_eq!(push(&outbound, "a", 1), Delivery::Queued);
+        assert_eq!(push(&outbound, "a", 2), Delivery::Overflow);
+        assert_eq!(push(&outbound, "a", 3), Delivery::Closed);
+    }
+
+    #[tokio::test]
+    async fn coalescing_keeps_order_per_point() {
+        let outbound = Outbound::new(8, SlowConsumerPolicy::Coalesce);
+
+        for (id, value) in [("a", 1), ("b", 2), ("a", 3)] {
+            push(&outbound, id, value);
+        }
+
+        assert_eq!(outbound.next().await.unwrap().packet, update("a", 3));
+
+        // The value of a was taken already, so the next one is queued behind b.
+        for (id, value) in [("a", 4), ("c", 5), ("b", 6), ("a", 7)] {
+            push(&outbound, id, value);
+        }
+
+        assert_eq!(
+            drain(&outbound).await,
+            vec![update("b", 6), update("a", 7), update("c", 5)]
+        );
+    }
+
+    #[tokio::test]
+    async fn held_updates_wait() {
+        let outbound = Outbound::new(8, SlowConsumerPolicy::Coalesce);
+        push(&outbound, "a", 1);
+
+        let held = outbound.hold();
+        push(&outbound, "b", 2);
+
+        let next = tokio::time::timeout(std::time::Duration::from_millis(10), outbound.next());
+        assert!(next.await.is_err());
+
+        drop(held);
+        assert_eq!(drain(&outbound).await, vec![update("a", 1), update("b", 2)]);
+    }
+}
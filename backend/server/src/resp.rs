@@ -0,0 +1,782 @@
+use bytes::{Buf, BytesMut};
+use schema::QuerySet;
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::{sleep_until, Instant};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+
+use crate::auth::Principal;
+use crate::gateway::{
+    escape_pattern, next_stream_id, Action, Gateway, Input, Rejection, Reply, Session, StreamId,
+    UpdateRx, UpdateTx,
+};
+use crate::listener::ACCEPT_BACKOFF;
+use crate::server::PointUpdateEvent;
+use crate::tls;
+use crate::values;
+
+/// Largest argument a client may send, keys and values alike.
+const MAX_BULK_SIZE: usize = 1024 * 1024;
+/// Most arguments a single command may have, e.g. keys of an MGET.
+const MAX_ARGUMENTS: usize = 64 * 1024;
+
+/// The name of a command and its arguments.
+type Command = Vec<Vec<u8>>;
+
+/// Accepts Redis-clients until the server stops listening. Keys are the full names of points,
+/// values are sent and received as text, see `values::to_text` and `values::from_text`.
+pub async fn accept(listener: TcpListener, tls: Option<TlsAcceptor>, gateway: Gateway) {
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(error = %e, "Could not accept RESP-client");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let tls = tls.clone();
+        let timeout = gateway.limits().auth_timeout;
+        let gateway = gateway.clone();
+
+        let span = info_span!("resp", peer = %address, principal = field::Empty);
+
+        tokio::spawn(
+            async move {
+                debug!("New RESP-client");
+
+                let result = match tls {
+                    Some(acceptor) => match tls::accept(&acceptor, stream, timeout).await {
+                        Ok((stream, principal)) => session(stream, principal, gateway).await,
+                        Err(e) => Err(e),
+                    },
+                    None => session(stream, None, gateway).await,
+                };
+
+                match result {
+                    Ok(_) => debug!("RESP-client disconnected"),
+                    Err(e) => info!(reason = %e, "RESP-client disconnected"),
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// The subscriptions of a client. Channels are points, patterns are globs like those of a
+/// Subscribe-packet. Each kind has its own stream, so a `pmessage` can't be mistaken for a
+/// `message`.
+struct Subscriptions {
+    channels: Vec<String>,
+    channel_stream: StreamId,
+    channel_tx: UpdateTx,
+    patterns: Vec<(String, QuerySet)>,
+    pattern_stream: StreamId,
+    pattern_tx: UpdateTx,
+}
+
+impl Subscriptions {
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
+
+enum Event {
+    Read(usize),
+    Channel(PointUpdateEvent),
+    Pattern(PointUpdateEvent),
+}
+
+struct Client<W> {
+    write: W,
+    gateway: Gateway,
+    /// Without credentials everyone starts out authenticated, otherwise `AUTH` is needed.
+    session: Result<Session, Rejection>,
+    subscriptions: Subscriptions,
+}
+
+async fn session<S>(stream: S, principal: Option<Principal>, gateway: Gateway) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut read, write) = tokio::io::split(stream);
+    let mut buffer = BytesMut::with_capacity(4096);
+
+    let (channel_tx, mut channel_rx) = gateway.update_channel();
+    let (pattern_tx, mut pattern_rx) = gateway.update_channel();
+
+    let mut client = Client {
+        write,
+        session: gateway.authenticate_principal(principal),
+        gateway,
+        subscriptions: Subscriptions {
+            channels: vec![],
+            channel_stream: next_stream_id(),
+            channel_tx,
+            patterns: vec![],
+            pattern_stream: next_stream_id(),
+            pattern_tx,
+        },
+    };
+
+    if let Ok(session) = &client.session {
+        Span::current().record("principal", session.principal.name.as_str());
+    }
+
+    // Like MQTT- and native clients, those that need to AUTH have to do so in time.
+    let limits = client.gateway.limits();
+    let max_command_size = limits.max_command_size;
+    let auth_deadline = Instant::now() + limits.auth_timeout;
+
+    loop {
+        while let Some(command) = parse_command(&mut buffer, max_command_size)? {
+            let mut out = vec![];
+            let quit = client.execute(command, &mut out).await;
+
+            client.write.write_all(&out).await?;
+
+            if quit {
+                return client.write.flush().await;
+            }
+        }
+
+        client.write.flush().await?;
+
+        let event = tokio::select! {
+            read = read.read_buf(&mut buffer) => Event::Read(read?),
+            Some(update) = channel_rx.recv() => Event::Channel(update),
+            Some(update) = pattern_rx.recv() => Event::Pattern(update),
+            _ = sleep_until(auth_deadline), if client.session.is_err() => {
+                return Err(Error::new(ErrorKind::TimedOut, "Authentication timed out."));
+            }
+        };
+
+        match event {
+            Event::Read(0) => return Ok(()),
+            Event::Read(_) => {}
+            Event::Channel(update) => client.channel_message(update).await?,
+            Event::Pattern(update) => client.pattern_message(update).await?,
+        }
+
+        // Updates queue up while a client is slow, so they are all sent before reading again.
+        drain(&mut client, &mut channel_rx, &mut pattern_rx).await?;
+    }
+}
+
+async fn drain<W>(
+    client: &mut Client<W>,
+    channel_rx: &mut UpdateRx,
+    pattern_rx: &mut UpdateRx,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Ok(update) = channel_rx.try_recv() {
+        client.channel_message(update).await?;
+    }
+
+    while let Ok(update) = pattern_rx.try_recv() {
+        client.pattern_message(update).await?;
+    }
+
+    Ok(())
+}
+
+impl<W> Client<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    async fn channel_message(&mut self, (id, value): PointUpdateEvent) -> Result<(), Error> {
+        let mut out = vec![];
+
+        write_array(&mut out, 3);
+        write_bulk(&mut out, b"message");
+        write_bulk(&mut out, id.as_str().as_bytes());
+        write_bulk(&mut out, &values::to_text(&value));
+
+        self.write.write_all(&out).await
+    }
+
+    /// Like Redis, a point matching several patterns is sent once for each of them.
+    async fn pattern_message(&mut self, (id, value): PointUpdateEvent) -> Result<(), Error> {
+        let mut out = vec![];
+        let text = values::to_text(&value);
+
+        for (pattern, set) in &self.subscriptions.patterns {
+            if set.matches(id.as_str()) {
+                write_array(&mut out, 4);
+                write_bulk(&mut out, b"pmessage");
+                write_bulk(&mut out, pattern.as_bytes());
+                write_bulk(&mut out, id.as_str().as_bytes());
+                write_bulk(&mut out, &text);
+            }
+        }
+
+        self.write.write_all(&out).await
+    }
+
+    /// Writes the reply to `out` and returns true if the client asked to quit.
+    async fn execute(&mut self, command: Command, out: &mut Vec<u8>) -> bool {
+        let mut arguments = command.into_iter();
+
+        let name = match arguments.next() {
+            Some(name) => String::from_utf8_lossy(&name).to_ascii_uppercase(),
+            None => return false,
+        };
+
+        // Values are kept as bytes, since blobs don't have to be UTF-8.
+        let raw: Vec<Vec<u8>> = arguments.collect();
+        let arguments: Vec<String> = raw
+            .iter()
+            .map(|a| String::from_utf8_lossy(a).into_owned())
+            .collect();
+
+        // Subscribed clients may only manage their subscriptions, like with Redis.
+        let subscribed = self.subscriptions.count() > 0;
+        let allowed = [
+            "SUBSCRIBE",
+            "PSUBSCRIBE",
+            "UNSUBSCRIBE",
+            "PUNSUBSCRIBE",
+            "PING",
+            "QUIT",
+        ];
+
+        if subscribed && !allowed.contains(&name.as_str()) {
+            write_error(
+                out,
+                &format!(
+                    "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context",
+                    name.to_ascii_lowercase()
+                ),
+            );
+            return false;
+        }
+
+        match (name.as_str(), arguments.len()) {
+            ("PING", 0) if subscribed => {
+                write_array(out, 2);
+                write_bulk(out, b"pong");
+                write_bulk(out, b"");
+            }
+            ("PING", 0) => out.extend_from_slice(b"+PONG\r\n"),
+            ("PING", 1) => write_bulk(out, &raw[0]),
+            ("QUIT", _) => {
+                out.extend_from_slice(b"+OK\r\n");
+                return true;
+            }
+            ("AUTH", 1) => self.auth("", &arguments[0], out).await,
+            ("AUTH", 2) => self.auth(&arguments[0], &arguments[1], out).await,
+            ("GET", 1) => self.get(&arguments[0], out).await,
+            ("MGET", n) if n > 0 => {
+                write_array(out, n);
+
+                for key in &arguments {
+                    match self.request(Action::Get(key.clone())).await {
+                        Ok(Reply::Point(point)) => write_value(out, point.value.as_ref()),
+                        _ => write_nil(out),
+                    }
+                }
+            }
+            ("SET", 2) => {
+                let input = Input::Text(raw[1].clone());
+
+                match self
+                    .request(Action::Update(arguments[0].clone(), input))
+                    .await
+                {
+                    Ok(_) => out.extend_from_slice(b"+OK\r\n"),
+                    Err(rejection) => write_rejection(out, &rejection),
+                }
+            }
+            ("SUBSCRIBE", n) if n > 0 => {
+                for channel in arguments {
+                    self.subscribe(channel, out).await;
+                }
+            }
+            ("PSUBSCRIBE", n) if n > 0 => {
+                for pattern in arguments {
+                    self.psubscribe(pattern, out).await;
+                }
+            }
+            ("UNSUBSCRIBE", _) => self.unsubscribe(arguments, out).await,
+            ("PUNSUBSCRIBE", _) => self.punsubscribe(arguments, out).await,
+            ("PING", _)
+            | ("AUTH", _)
+            | ("GET", _)
+            | ("MGET", _)
+            | ("SET", _)
+            | ("SUBSCRIBE", _)
+            | ("PSUBSCRIBE", _) => write_error(
+                out,
+                &format!(
+                    "ERR wrong number of arguments for '{}' command",
+                    name.to_ascii_lowercase()
+                ),
+            ),
+            _ => write_error(
+                out,
+                &format!("ERR unknown command '{}'", name.to_ascii_lowercase()),
+            ),
+        }
+
+        false
+    }
+
+    async fn request(&self, action: Action) -> Result<Reply, Rejection> {
+        match &self.session {
+            Ok(session) => self.gateway.request(session, action).await,
+            Err(rejection) => Err(rejection.clone()),
+        }
+    }
+
+    async fn auth(&mut self, user: &str, secret: &str, out: &mut Vec<u8>) {
+        self.session = self.gateway.authenticate(user, secret).await;
+
+        match &self.session {
+            Ok(session) => {
+                Span::current().record("principal", session.principal.name.as_str());
+                out.extend_from_slice(b"+OK\r\n")
+            }
+            Err(_) => write_error(
+                out,
+                "WRONGPASS invalid username-password pair or user is disabled.",
+            ),
+        }
+    }
+
+    /// Points that don't exist are `nil` like missing keys, instead of an error.
+    async fn get(&self, key: &str, out: &mut Vec<u8>) {
+        match self.request(Action::Get(key.to_string())).await {
+            Ok(Reply::Point(point)) => write_value(out, point.value.as_ref()),
+            Ok(_) | Err(Rejection::NotFound(_)) => write_nil(out),
+            Err(rejection) => write_rejection(out, &rejection),
+        }
+    }
+
+    async fn subscribe(&mut self, channel: String, out: &mut Vec<u8>) {
+        if !self.subscriptions.channels.contains(&channel) {
+            let subscribe = Action::Subscribe {
+                stream: self.subscriptions.channel_stream,
+                pattern: escape_pattern(&channel),
+                tx: self.subscriptions.channel_tx.clone(),
+            };
+
+            if let Err(rejection) = self.request(subscribe).await {
+                write_rejection(out, &rejection);
+                return;
+            }
+
+            self.subscriptions.channels.push(channel.clone());
+        }
+
+        write_confirmation(out, "subscribe", Some(&channel), self.subscriptions.count());
+    }
+
+    async fn psubscribe(&mut self, pattern: String, out: &mut Vec<u8>) {
+        if !self
+            .subscriptions
+            .patterns
+            .iter()
+            .any(|(p, _)| *p == pattern)
+        {
+            let set = match QuerySet::single(&pattern) {
+                Ok(set) => set,
+                Err(e) => {
+                    write_error(out, &format!("ERR {}", e));
+                    return;
+                }
+            };
+
+            let subscribe = Action::Subscribe {
+                stream: self.subscriptions.pattern_stream,
+                pattern: pattern.clone(),
+                tx: self.subscriptions.pattern_tx.clone(),
+            };
+
+            if let Err(rejection) = self.request(subscribe).await {
+                write_rejection(out, &rejection);
+                return;
+            }
+
+            self.subscriptions.patterns.push((pattern.clone(), set));
+        }
+
+        write_confirmation(
+            out,
+            "psubscribe",
+            Some(&pattern),
+            self.subscriptions.count(),
+        );
+    }
+
+    /// Without arguments every channel is unsubscribed.
+    async fn unsubscribe(&mut self, channels: Vec<String>, out: &mut Vec<u8>) {
+        let channels = match channels.is_empty() {
+            true => self.subscriptions.channels.clone(),
+            false => channels,
+        };
+
+        if channels.is_empty() {
+            write_confirmation(out, "unsubscribe", None, self.subscriptions.count());
+        }
+
+        for channel in channels {
+            self.subscriptions.channels.retain(|c| *c != channel);
+
+            let unsubscribe = Action::Unsubscribe {
+                stream: self.subscriptions.channel_stream,
+                pattern: escape_pattern(&channel),
+            };
+            let _ = self.request(unsubscribe).await;
+
+            write_confirmation(
+                out,
+                "unsubscribe",
+                Some(&channel),
+                self.subscriptions.count(),
+            );
+        }
+    }
+
+    async fn punsubscribe(&mut self, patterns: Vec<String>, out: &mut Vec<u8>) {
+        let patterns = match patterns.is_empty() {
+            true => self
+                .subscriptions
+                .patterns
+                .iter()
+                .map(|(p, _)| p.clone())
+                .collect(),
+            false => patterns,
+        };
+
+        if patterns.is_empty() {
+            write_confirmation(out, "punsubscribe", None, self.subscriptions.count());
+        }
+
+        for pattern in patterns {
+            self.subscriptions.patterns.retain(|(p, _)| *p != pattern);
+
+            let unsubscribe = Action::Unsubscribe {
+                stream: self.subscriptions.pattern_stream,
+                pattern: pattern.clone(),
+            };
+            let _ = self.request(unsubscribe).await;
+
+            write_confirmation(
+                out,
+                "punsubscribe",
+                Some(&pattern),
+                self.subscriptions.count(),
+            );
+        }
+    }
+}
+
+/// Takes the next complete command off the buffer, either a RESP-array of bulk-strings like
+/// client-libraries send, or an inline command like `GET plant/speed` typed into telnet.
+/// Incomplete commands stay in the buffer, up to `max_size` bytes.
+fn parse_command(buffer: &mut BytesMut, max_size: usize) -> Result<Option<Command>, Error> {
+    let parsed = match buffer.first() {
+        None => return Ok(None),
+        Some(b'*') => parse_array(buffer, max_size)?,
+        Some(_) => line(buffer, 0).map(|(text, next)| {
+            let command = text
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|a| !a.is_empty())
+                .map(|a| a.to_vec())
+                .collect();
+
+            (command, next)
+        }),
+    };
+
+    let (command, consumed) = match parsed {
+        Some(parsed) => parsed,
+        None if buffer.len() > max_size => return Err(invalid("Command too long.")),
+        None => return Ok(None),
+    };
+
+    buffer.advance(consumed);
+
+    Ok(Some(command))
+}
+
+fn parse_array(buffer: &[u8], max_size: usize) -> Result<Option<(Command, usize)>, Error> {
+    let (count, mut index) = match line(buffer, 1) {
+        Some((count, next)) => (number(count)?, next),
+        None => return Ok(None),
+    };
+
+    if count > MAX_ARGUMENTS {
+        return Err(invalid("Too many arguments."));
+    }
+
+    let mut arguments = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        if buffer.get(index) != Some(&b'$') && index < buffer.len() {
+            return Err(invalid("Expected bulk-string."));
+        }
+
+        let length = match line(buffer, index + 1) {
+            Some((length, next)) => {
+                index = next;
+                number(length)?
+            }
+            None => return Ok(None),
+        };
+
+        if length > MAX_BULK_SIZE {
+            return Err(invalid("Bulk-string too long."));
+        }
+
+        // Rejected before it is buffered, not once it is complete.
+        if index + length + 2 > max_size {
+            return Err(invalid("Command too long."));
+        }
+
+        if buffer.len() < index + length + 2 {
+            return Ok(None);
+        }
+
+        arguments.push(buffer[index..index + length].to_vec());
+        index += length + 2;
+    }
+
+    Ok(Some((arguments, index)))
+}
+
+/// The bytes from `start` up to the next CRLF, and the index after it.
+fn line(buffer: &[u8], start: usize) -> Option<(&[u8], usize)> {
+    let end = buffer.get(start..)?.iter().position(|b| *b == b'\n')? + start;
+    let text = &buffer[start..end];
+    let text = text.strip_suffix(b"\r").unwrap_or(text);
+
+    Some((text, end + 1))
+}
+
+fn number(text: &[u8]) -> Result<usize, Error> {
+    std::str::from_utf8(text)
+        .ok()
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(|| invalid("Invalid length."))
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn write_array(out: &mut Vec<u8>, length: usize) {
+    out.extend_from_slice(format!("*{}\r\n", length).as_bytes());
+}
+
+fn write_bulk(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\r\n");
+}
+
+fn write_nil(out: &mut Vec<u8>) {
+    out.extend_from_slice(b"$-1\r\n");
+}
+
+/// Points that were never written are `nil`.
+fn write_value(out: &mut Vec<u8>, value: Option<&protocol::Value>) {
+    match value {
+        Some(value) => write_bulk(out, &values::to_text(value)),
+        None => write_nil(out),
+    }
+}
+
+fn write_error(out: &mut Vec<u8>, message: &str) {
+    // Errors are simple strings, which can't contain line-breaks.
+    let message = message.replace(['\r', '\n'], " ");
+
+    out.extend_from_slice(format!("-{}\r\n", message).as_bytes());
+}
+
+fn write_rejection(out: &mut Vec<u8>, rejection: &Rejection) {
+    let prefix = match rejection {
+        Rejection::Unauthenticated(_) => "NOAUTH",
+        Rejection::Forbidden(_) => "NOPERM",
+        _ => "ERR",
+    };
+
+    write_error(out, &format!("{} {}", prefix, rejection.message()));
+}
+
+fn write_confirmation(out: &mut Vec<u8>, kind: &str, name: Option<&str>, count: usize) {
+    write_array(out, 3);
+    write_bulk(out, kind.as_bytes());
+
+    match name {
+        Some(name) => write_bulk(out, name.as_bytes()),
+        None => write_nil(out),
+    }
+
+    out.extend_from_slice(format!(":{}\r\n", count).as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Credentials;
+    use crate::config::Limits;
+    use crate::listener::Listener;
+    use crate::testing::{producer, request, secured, spawn_server, update, PCT};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    fn args(arguments: &[&str]) -> Vec<Vec<u8>> {
+        arguments.iter().map(|a| a.as_bytes().to_vec()).collect()
+    }
+
+    async fn command(stream: &mut BufReader<TcpStream>, command: &str) -> String {
+        stream.write_all(command.as_bytes()).await.unwrap();
+        stream.write_all(b"\r\n").await.unwrap();
+
+        let mut reply = String::new();
+        stream.read_line(&mut reply).await.unwrap();
+        reply
+    }
+
+    async fn expect(stream: &mut BufReader<TcpStream>, expected: &str) {
+        let mut received = vec![0; expected.len()];
+        stream.read_exact(&mut received).await.unwrap();
+
+        assert_eq!(String::from_utf8(received).unwrap(), expected);
+    }
+
+    #[test]
+    fn parses_commands() {
+        let mut buffer =
+            BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$11\r\nplant/speed\r\nPING\r\n*1\r\n$4"[..]);
+
+        assert_eq!(
+            parse_command(&mut buffer, 1024).unwrap(),
+            Some(args(&["GET", "plant/speed"]))
+        );
+        assert_eq!(
+            parse_command(&mut buffer, 1024).unwrap(),
+            Some(args(&["PING"]))
+        );
+        assert_eq!(parse_command(&mut buffer, 1024).unwrap(), None);
+
+        buffer.extend_from_slice(b"\r\nQUIT\r\n");
+        assert_eq!(
+            parse_command(&mut buffer, 1024).unwrap(),
+            Some(args(&["QUIT"]))
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        let mut buffer = BytesMut::from(&b"*1\r\n+GET\r\n"[..]);
+        assert!(parse_command(&mut buffer, 1024).is_err());
+
+        let mut buffer = BytesMut::from(&b"*x\r\n"[..]);
+        assert!(parse_command(&mut buffer, 1024).is_err());
+    }
+
+    #[test]
+    fn rejects_commands_beyond_the_limit() {
+        // Declared larger than the limit, rejected before the value arrives.
+        let mut buffer = BytesMut::from(&b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$2000\r\n"[..]);
+        assert!(parse_command(&mut buffer, 1024).is_err());
+
+        // Many small arguments add up as well.
+        let mut buffer = BytesMut::from(&b"*1000\r\n"[..]);
+        for _ in 0..200 {
+            buffer.extend_from_slice(b"$1\r\na\r\n");
+        }
+        assert!(parse_command(&mut buffer, 1024).is_err());
+
+        let mut buffer = BytesMut::from(&[b'x'; 2048][..]);
+        assert!(parse_command(&mut buffer, 1024).is_err());
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_clients_time_out() {
+        let (tx, _rx) = unbounded_channel();
+        let credentials =
+            Credentials::parse("[[tokens]]\nprincipal = \"dashboard\"\ntoken = \"secret\"")
+                .unwrap();
+        let limits = Limits {
+            auth_timeout: Duration::from_millis(50),
+            ..Limits::default()
+        };
+        let gateway = Gateway::new(tx, Arc::new(Some(credentials)), Arc::new(None), limits);
+
+        let (mut client, stream) = tokio::io::duplex(1024);
+        client.write_all(b"PING\r\n").await.unwrap();
+
+        let result = session(stream, None, gateway).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"+PONG\r\n");
+    }
+
+    #[tokio::test]
+    async fn clients_get_updates() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = spawn_server(secured(vec![Listener::Resp(listener)]));
+
+        let mut producer = producer(&server.handle).await;
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut dashboard = BufReader::new(stream);
+
+        assert!(command(&mut dashboard, "GET plant/line1/speed")
+            .await
+            .starts_with("-NOAUTH"));
+        assert!(command(&mut dashboard, "AUTH wrong-token")
+            .await
+            .starts_with("-WRONGPASS"));
+        assert_eq!(
+            command(&mut dashboard, "AUTH dashboard-token").await,
+            "+OK\r\n"
+        );
+        assert!(command(&mut dashboard, "SET plant/line1/speed 1")
+            .await
+            .starts_with("-NOPERM"));
+        assert!(command(&mut dashboard, "PSUBSCRIBE **")
+            .await
+            .starts_with("-NOPERM"));
+        assert_eq!(
+            command(&mut dashboard, "PSUBSCRIBE plant/**").await,
+            "*3\r\n"
+        );
+        expect(
+            &mut dashboard,
+            "$10\r\npsubscribe\r\n$8\r\nplant/**\r\n:1\r\n",
+        )
+        .await;
+
+        // The dashboard may not read the second line, so it only gets the first.
+        assert_eq!(
+            request(&mut producer, update("plant/line2/speed", 1)).await,
+            PCT::Ok {}
+        );
+        assert_eq!(
+            request(&mut producer, update("plant/line1/speed", 2)).await,
+            PCT::Ok {}
+        );
+
+        expect(
+            &mut dashboard,
+            "*4\r\n$8\r\npmessage\r\n$8\r\nplant/**\r\n$17\r\nplant/line1/speed\r\n$1\r\n2\r\n",
+        )
+        .await;
+    }
+}
@@ -0,0 +1,227 @@
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use schema::PointType;
+use serde::Deserialize;
+use serde_json::json;
+use std::io::Error;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::error;
+
+use crate::gateway::{Action, Gateway, Input, Rejection, Reply};
+use crate::graphql;
+use crate::monitoring;
+use crate::sse;
+use crate::tls::TlsListener;
+use crate::values;
+
+#[derive(Debug, Deserialize)]
+struct PointsQuery {
+    query: Option<String>,
+}
+
+/// Serves the JSON gateway until the listener fails:
+///
+/// - `GET /points/{ns}/{point}`
+/// - `PUT /points/{ns}/{point}` with `{ "value": 42 }` and an optional `"type": "u8"`
+/// - `GET /points?query=glob`
+/// - `GET /schema`
+/// - `GET /stream?query=glob`, see `sse::stream`
+/// - `GET /metrics`, the server's own metrics for Prometheus
+/// - `POST /graphql` and subscriptions on `GET /graphql`, see `graphql::router`
+pub async fn serve(listener: TcpListener, tls: Option<TlsAcceptor>, gateway: Gateway) {
+    let timeout = gateway.limits().auth_timeout;
+    let router = Router::new()
+        .route("/points", get(query_points))
+        .route("/points/{*key}", get(get_point).put(put_point))
+        .route("/schema", get(get_schema))
+        .route("/stream", get(sse::stream))
+        .route("/metrics", get(metrics))
+        .with_state(gateway.clone())
+        .merge(graphql::router(gateway));
+
+    if let Err(e) = serve_router(listener, tls, timeout, router).await {
+        error!(error = %e, "HTTP-server stopped");
+    }
+}
+
+/// Over HTTPS when TLS is configured, so credentials in headers are never sent in plaintext.
+pub async fn serve_router(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    timeout: Duration,
+    router: Router,
+) -> Result<(), Error> {
+    match tls {
+        Some(acceptor) => axum::serve(TlsListener::new(listener, acceptor, timeout), router).await,
+        None => axum::serve(listener, router).await,
+    }
+}
+
+async fn get_point(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+    Path(key): Path<String>,
+) -> Result<Json<serde_json::Value>, Rejected> {
+    dispatch(&gateway, &headers, Action::Get(key)).await
+}
+
+async fn put_point(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+    Path(key): Path<String>,
+    Json(body): Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, Rejected> {
+    let value = match body.get("value") {
+        Some(value) => value.clone(),
+        None => return Err(Rejection::Invalid(String::from("Missing value.")).into()),
+    };
+
+    // An explicit type is checked by update_point, like the type of a packet.
+    let input = match body.get("type") {
+        Some(point_type) => point_type
+            .as_str()
+            .and_then(PointType::from_name)
+            .and_then(|t| values::convert(&value, t))
+            .map(Input::Typed)
+            .ok_or_else(|| Rejection::Invalid(String::from("Invalid point-type.")))?,
+        None => Input::Json(value),
+    };
+
+    dispatch(&gateway, &headers, Action::Update(key, input)).await
+}
+
+async fn query_points(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+    Query(query): Query<PointsQuery>,
+) -> Result<Json<serde_json::Value>, Rejected> {
+    let query = query.query.unwrap_or_else(|| String::from("**"));
+
+    dispatch(&gateway, &headers, Action::Query(query)).await
+}
+
+async fn get_schema(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, Rejected> {
+    dispatch(&gateway, &headers, Action::Schema).await
+}
+
+/// Unauthenticated like most exporters, there are no point-values in it.
+async fn metrics() -> impl IntoResponse {
+    let content_type = "text/plain; version=0.0.4";
+
+    ([(header::CONTENT_TYPE, content_type)], monitoring::render())
+}
+
+async fn dispatch(
+    gateway: &Gateway,
+    headers: &HeaderMap,
+    action: Action,
+) -> Result<Json<serde_json::Value>, Rejected> {
+    let session = gateway.authenticate_header(authorization(headers)).await?;
+
+    let body = match gateway.request(&session, action).await? {
+        Reply::Point(point) => values::point_json(&point.id, point.value.as_ref()),
+        Reply::Points(points) => points
+            .iter()
+            .map(|p| values::point_json(&p.id, p.value.as_ref()))
+            .collect(),
+        Reply::Schema(points) => points
+            .iter()
+            .map(|p| {
+                let types: Vec<_> = p.types.iter().map(|t| t.as_str()).collect();
+
+                json!({
+                    "id": p.id,
+                    "namespace": p.namespace,
+                    "name": p.name,
+                    "types": types,
+                })
+            })
+            .collect(),
+        Reply::Done => json!({}),
+    };
+
+    Ok(Json(body))
+}
+
+pub fn authorization(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+}
+
+/// A rejection as a JSON error-body with the matching status.
+pub struct Rejected(Rejection);
+
+impl From<Rejection> for Rejected {
+    fn from(rejection: Rejection) -> Self {
+        Rejected(rejection)
+    }
+}
+
+impl IntoResponse for Rejected {
+    fn into_response(self) -> Response {
+        let status = match &self.0 {
+            Rejection::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            Rejection::Forbidden(_) => StatusCode::FORBIDDEN,
+            Rejection::NotFound(_) => StatusCode::NOT_FOUND,
+            Rejection::Invalid(_) => StatusCode::BAD_REQUEST,
+            Rejection::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        };
+
+        (status, Json(json!({ "error": self.0.message() }))).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::listener::Listener;
+    use crate::testing::{http, producer, secured, spawn_server};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn points_are_read_and_written_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = spawn_server(secured(vec![Listener::Http(listener)]));
+
+        let _producer = producer(&server.handle).await;
+        let get = "GET /points/plant/line1/speed";
+        let put = "PUT /points/plant/line1/speed";
+        let value = Some(r#"{ "value": 7 }"#);
+
+        assert_eq!(http(address, get, None, None).await.0, 401);
+        assert_eq!(http(address, get, Some("wrong-token"), None).await.0, 401);
+        assert_eq!(
+            http(address, put, Some("dashboard-token"), value).await.0,
+            403
+        );
+
+        let line2 = "GET /points/plant/line2/speed";
+        assert_eq!(
+            http(address, line2, Some("dashboard-token"), None).await.0,
+            403
+        );
+
+        assert_eq!(
+            http(address, put, Some("producer-token"), value).await.0,
+            200
+        );
+
+        let (status, body) = http(address, get, Some("dashboard-token"), None).await;
+        let point: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(status, 200);
+        assert_eq!(
+            point,
+            serde_json::json!({ "id": "plant/line1/speed", "type": "i32", "value": 7 })
+        );
+    }
+}
@@ -1,124 +1,637 @@
+use futures_util::FutureExt;
 use protocol::Value;
 use protocol::{Packet, StringKey};
 use store::ValueStore;
-use store::rocksdb::DB;
-use store::rocksdb::create_rocksdb;
-use std::io::Error;
-use tokio::net::TcpListener;
-use tokio::sync::mpsc::UnboundedSender;
-use tokio::{net::TcpStream, sync::mpsc::unbounded_channel};
-use tokio_stream::{
-    wrappers::{TcpListenerStream, UnboundedReceiverStream},
-    StreamExt,
+use store::rocksdb::RocksStore;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{channel, unbounded_channel};
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, Mutex as AsyncMutex, MutexGuard};
+use tokio::task::JoinSet;
+use tokio::time::{timeout_at, Instant};
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::{ReceiverStream, UnboundedReceiverStream};
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, info, Instrument};
+
+use crate::acl::Acl;
+use crate::auth::Credentials;
+use crate::config::Limits;
+use crate::connection::{Address, ConnectionId, Connections, Incoming};
+use crate::listener::{accept, IncomingTx, Listener};
+#[cfg(unix)]
+use crate::listener::accept_unix;
+use crate::exporter;
+use crate::gateway::{EventStream, Gateway, Rejection, Reply, Request, StreamId};
+use crate::grpc;
+use crate::monitoring;
+use crate::mqtt;
+use crate::resp;
+use crate::rest;
+use crate::subscriptions::SubscriptionIndex;
+use crate::event_handlers::{
+    connection_error, handle_new_connection, handle_request, server_error,
 };
 
-use crate::connection::{Connection, ConnectionId};
-use crate::event_handlers::{connection_error, handle_new_connection, handle_packet, point_update, server_error};
+/// How long a shutdown may take, unless configured.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Points are spread over this many locks, see `PointLocks`.
+const POINT_LOCKS: usize = 256;
 
-type PacketTx = UnboundedSender<(ConnectionId, Result<Packet<StringKey>, Error>)>;
-type PointTx = UnboundedSender<(StringKey, Value)>;
-type RocksDBStore = ValueStore<DB>;
+/// Bounded by `Limits::packet_queue`, read-loops wait while it is full.
+pub type ErrorTx = Sender<ConnectionErrorEvent>;
+pub type RocksDBStore = ValueStore<RocksStore>;
+/// Schemas registered through the gateway by principal. They have no connection to outlive.
+pub type GatewaySchemas = BTreeMap<String, String>;
+pub type EventStreams = BTreeMap<StreamId, EventStream>;
 
-pub type ConnectionEvent = TcpStream;
+pub type ConnectionEvent = Incoming;
 pub type PacketEvent = (ConnectionId, Packet<StringKey>);
 pub type ConnectionErrorEvent = (ConnectionId, Error);
 pub type ServerErrorEvent = Error;
 pub type PointUpdateEvent = (StringKey, Value);
+pub type RequestEvent = (Request, oneshot::Sender<Result<Reply, Rejection>>);
+
+/// What the handlers share. Packets are handled by the read-loop of their connection and
+/// requests by a task of their own, so both run on every thread of the runtime. Only connecting
+/// and disconnecting go through the event-loop.
+///
+/// No lock is held across an await. Those that are taken together are taken in the order
+/// `connections`, `streams`, `subscriptions`.
+pub struct State {
+    pub store: RocksDBStore,
+    pub connections: RwLock<Connections>,
+    pub streams: RwLock<EventStreams>,
+    pub subscriptions: RwLock<SubscriptionIndex>,
+    /// Held while the schema is rebuilt, so concurrent registrations don't lose each other.
+    pub schemas: Mutex<GatewaySchemas>,
+    pub points: PointLocks,
+    pub errors: ErrorTx,
+    pub credentials: Arc<Option<Credentials>>,
+    pub acl: Arc<Option<Acl>>,
+    pub limits: Limits,
+}
+
+/// A write holds the lock of its point until the update is queued for the subscribers, so they
+/// get the updates of a point in the order they were stored. Writes of other points go on, unless
+/// they share the lock.
+pub struct PointLocks(Vec<AsyncMutex<()>>);
 
-pub type EventContext<'a> = (&'a mut RocksDBStore, &'a mut Vec<Connection>, &'a PacketTx, &'a PointTx);
+impl PointLocks {
+    fn new(count: usize) -> Self {
+        PointLocks((0..count).map(|_| AsyncMutex::new(())).collect())
+    }
+
+    pub async fn lock(&self, id: &StringKey) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+
+        self.0[(hasher.finish() % self.0.len() as u64) as usize]
+            .lock()
+            .await
+    }
+}
 
 #[derive(Debug)]
 enum Event {
     Connection(ConnectionEvent),
-    Packet(PacketEvent),
     ConnectionError(ConnectionErrorEvent),
     ServerError(ServerErrorEvent),
-    PointUpdate(PointUpdateEvent)
+    Request(RequestEvent),
+    Shutdown,
 }
 
 pub struct Server {
-    listener: TcpListenerStream,
-    connections: Vec<Connection>,
-    store: RocksDBStore,
+    listeners: Vec<Listener>,
+    tls: Option<TlsAcceptor>,
+    incoming_tx: IncomingTx,
+    incoming_rx: Option<UnboundedReceiver<Result<Incoming, Error>>>,
+    shutdown_tx: UnboundedSender<()>,
+    shutdown_rx: Option<UnboundedReceiver<()>>,
+    error_rx: Option<Receiver<ConnectionErrorEvent>>,
+    requests: JoinSet<()>,
+    state: Arc<State>,
 }
 
 impl Server {
-    pub fn new(listener: TcpListener) -> Self {
+    /// Without credentials every connection is treated as authenticated,
+    /// without an acl every principal may do everything.
+    pub fn new(
+        store: RocksDBStore,
+        listeners: Vec<Listener>,
+        tls: Option<TlsAcceptor>,
+        credentials: Option<Credentials>,
+        acl: Option<Acl>,
+        limits: Limits,
+    ) -> Self {
+        let (incoming_tx, incoming_rx) = unbounded_channel();
+        let (shutdown_tx, shutdown_rx) = unbounded_channel();
+        let (error_tx, error_rx) = channel(limits.packet_queue);
+        monitoring::install();
+
+        let state = State {
+            store,
+            connections: RwLock::new(Connections::default()),
+            streams: RwLock::new(EventStreams::new()),
+            subscriptions: RwLock::new(SubscriptionIndex::new()),
+            schemas: Mutex::new(GatewaySchemas::new()),
+            points: PointLocks::new(POINT_LOCKS),
+            errors: error_tx,
+            credentials: Arc::new(credentials),
+            acl: Arc::new(acl),
+            limits,
+        };
+
         Server {
-            listener: TcpListenerStream::new(listener),
-            connections: vec![],
-            store: create_rocksdb("./db"),
+            listeners,
+            tls,
+            incoming_tx,
+            incoming_rx: Some(incoming_rx),
+            shutdown_tx,
+            shutdown_rx: Some(shutdown_rx),
+            error_rx: Some(error_rx),
+            requests: JoinSet::new(),
+            state: Arc::new(state),
         }
     }
 
-    pub async fn run(&mut self) {
-        let (packet_tx, packet_rx) = unbounded_channel();
-        let packet_rx = UnboundedReceiverStream::new(packet_rx);
+    /// Returns a handle to attach connections that don't come from a listener, or to stop the
+    /// server.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            incoming_tx: self.incoming_tx.clone(),
+            shutdown_tx: self.shutdown_tx.clone(),
+        }
+    }
 
-        let (point_tx, point_rx) = unbounded_channel();
-        let point_rx = UnboundedReceiverStream::new(point_rx);
+    /// Handles events until SIGINT, SIGTERM or `ServerHandle::shutdown`, then shuts down within
+    /// `Limits::shutdown_timeout`, see `shut_down`. Fails if that took longer or the store could
+    /// not be flushed.
+    pub async fn run(&mut self) -> Result<(), Error> {
+        let incoming_tx = self.incoming_tx.clone();
+        let incoming_rx = self.incoming_rx.take().expect("Server is already running.");
+        let shutdown_rx = self.shutdown_rx.take().expect("Server is already running.");
+        let error_rx = self.error_rx.take().expect("Server is already running.");
+        let (request_tx, request_rx) = unbounded_channel();
+        let gateway = Gateway::new(
+            request_tx,
+            self.state.credentials.clone(),
+            self.state.acl.clone(),
+            self.state.limits.clone(),
+        );
 
-        let listener = &mut self.listener;
-        let new_connections = listener.map(|x| transform_connection(x));
-        let packets = packet_rx.map(|(id, packet)| transform_packet(id, packet));
-        let points = point_rx.map(|(point, value)| transform_point_update(point, value));
+        let mut tasks = vec![];
 
-        let mut events = new_connections
-            .merge(packets)
-            .merge(points);
+        tasks.push(tokio::spawn(async {
+            let mut interval = tokio::time::interval(monitoring::UPKEEP_INTERVAL);
 
-        loop {
-            let event = events.next().await;
+            loop {
+                interval.tick().await;
+                monitoring::install().run_upkeep();
+            }
+        }));
 
-            let ctx: EventContext = (
-                &mut self.store,
-                &mut self.connections,
-                &packet_tx,
-                &point_tx,
-            );
+        let shutdown_tx = self.shutdown_tx.clone();
 
-            match event {
-                Some(Event::Connection(stream)) => {
-                    handle_new_connection(ctx, stream);
+        tasks.push(tokio::spawn(async move {
+            shutdown_signal().await;
+            let _ = shutdown_tx.send(());
+        }));
+
+        for listener in self.listeners.drain(..) {
+            let task = match listener {
+                Listener::Tcp(listener) => {
+                    let tls = self.tls.clone();
+                    let timeout = self.state.limits.auth_timeout;
+                    tokio::spawn(accept(listener, false, tls, timeout, incoming_tx.clone()))
+                }
+                Listener::WebSocket(listener) => {
+                    let tls = self.tls.clone();
+                    let timeout = self.state.limits.auth_timeout;
+                    tokio::spawn(accept(listener, true, tls, timeout, incoming_tx.clone()))
+                }
+                Listener::Http(listener) => {
+                    let tls = self.tls.clone();
+                    tokio::spawn(rest::serve(listener, tls, gateway.clone()))
                 }
-                Some(Event::Packet(e)) => {
-                    handle_packet(ctx, e).await;
+                Listener::Mqtt(listener) => {
+                    let tls = self.tls.clone();
+                    tokio::spawn(mqtt::accept(listener, tls, gateway.clone()))
                 }
-                Some(Event::ConnectionError(e)) => {
-                    connection_error(ctx, e);
+                Listener::Resp(listener) => {
+                    let tls = self.tls.clone();
+                    tokio::spawn(resp::accept(listener, tls, gateway.clone()))
                 }
-                Some(Event::PointUpdate(e)) => {
-                    point_update(ctx, e);
+                Listener::Grpc(listener) => {
+                    let tls = self.tls.clone();
+                    tokio::spawn(grpc::serve(listener, tls, gateway.clone()))
                 }
-                Some(Event::ServerError(e)) => {
-                    server_error(ctx, e);
+                Listener::Exporter(listener, query) => {
+                    let tls = self.tls.clone();
+                    tokio::spawn(exporter::serve(listener, tls, query, gateway.clone()))
                 }
-                None => break,
+                #[cfg(unix)]
+                Listener::Unix(listener, path) => {
+                    tokio::spawn(accept_unix(listener, path, incoming_tx.clone()))
+                }
+            };
+
+            tasks.push(task);
+        }
+
+        let new_connections = UnboundedReceiverStream::new(incoming_rx).map(transform_connection);
+        let errors = ReceiverStream::new(error_rx).map(Event::ConnectionError);
+        let requests = UnboundedReceiverStream::new(request_rx).map(Event::Request);
+        let shutdown = UnboundedReceiverStream::new(shutdown_rx).map(|_| Event::Shutdown);
+
+        let mut events = new_connections
+            .merge(errors)
+            .merge(requests)
+            .merge(shutdown);
+
+        loop {
+            match events.next().await {
+                Some(Event::Shutdown) | None => break,
+                Some(event) => self.dispatch(event),
             }
         }
+
+        // Without their listeners, new connections are refused by the OS.
+        for task in tasks {
+            task.abort();
+        }
+
+        self.shut_down(&mut events).await
+    }
+
+    fn dispatch(&mut self, event: Event) {
+        let state = &self.state;
+
+        match event {
+            Event::Connection(incoming) => {
+                handle_new_connection(state, incoming);
+            }
+            Event::ConnectionError(e) => {
+                connection_error(state, e);
+            }
+            Event::Request(e) => {
+                self.requests.spawn(handle_request(state.clone(), e));
+            }
+            Event::ServerError(e) => {
+                server_error(e);
+            }
+            Event::Shutdown => {}
+        }
+
+        // Finished requests are only forgotten once they are taken from the set.
+        while self.requests.try_join_next().is_some() {}
+    }
+
+    /// Sends a Shutdown-packet to every connection, handles the requests that are already
+    /// queued, waits for the updates to be written and closes the connections. The store is
+    /// flushed even if the deadline passed.
+    async fn shut_down<S>(&mut self, events: &mut S) -> Result<(), Error>
+    where
+        S: Stream<Item = Event> + Unpin,
+    {
+        let connections = self.state.connections.read().unwrap().len();
+        info!(connections, "Shutting down");
+
+        let deadline = Instant::now() + self.state.limits.shutdown_timeout;
+        let drained = timeout_at(deadline, self.drain(events)).await;
+
+        // Dropping them aborts their read-loops and closes the sockets.
+        self.requests.abort_all();
+        self.state.connections.write().unwrap().clear();
+        self.state.streams.write().unwrap().clear();
+
+        let flushed = self.state.store.flush().await;
+
+        match (drained, flushed) {
+            (Ok(_), Ok(_)) => Ok(()),
+            (Err(_), _) => Err(Error::new(
+                ErrorKind::TimedOut,
+                "Clients could not be drained within the shutdown-timeout.",
+            )),
+            (_, Err(e)) => Err(e),
+        }
+    }
+
+    async fn drain<S>(&mut self, events: &mut S)
+    where
+        S: Stream<Item = Event> + Unpin,
+    {
+        let mut writes = JoinSet::new();
+
+        for connection in self.state.connections.read().unwrap().iter() {
+            let writer = connection.writer();
+
+            let notice = async move {
+                let mut writer = writer.lock().await;
+                Packet::<StringKey>::Shutdown {}
+                    .write_to(&mut *writer)
+                    .await?;
+                writer.flush().await
+            };
+
+            writes.spawn(notice.instrument(connection.span().clone()));
+        }
+
+        while let Some(result) = writes.join_next().await {
+            if let Ok(Err(e)) = result {
+                debug!(error = %e, "Could not send Shutdown-packet");
+            }
+        }
+
+        // Read-loops still handle the packets that arrive, as do the requests that are queued.
+        // New connections are not accepted anymore.
+        while let Some(Some(event)) = events.next().now_or_never() {
+            match event {
+                Event::Connection(_) | Event::Shutdown => {}
+                event => self.dispatch(event),
+            }
+        }
+
+        while self.requests.join_next().await.is_some() {}
+
+        let mut finished = JoinSet::new();
+
+        for connection in self.state.connections.read().unwrap().iter() {
+            finished.spawn(connection.finish());
+        }
+
+        while finished.join_next().await.is_some() {}
     }
 }
 
-fn transform_connection(data: std::io::Result<TcpStream>) -> Event {
+/// Resolves on SIGINT, and on SIGTERM on unix. Never resolves if the handlers can't be installed.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
+
+/// Attaches connections to a server, before or while it runs.
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    incoming_tx: IncomingTx,
+    shutdown_tx: UnboundedSender<()>,
+}
+
+impl ServerHandle {
+    /// Attaches any byte-stream, e.g. one half of `tokio::io::duplex`. The connection goes through
+    /// the same authentication as one from a listener.
+    pub fn connect<S>(&self, stream: S) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read, write) = tokio::io::split(stream);
+
+        self.attach(Incoming {
+            read: Box::new(read),
+            write: Box::new(write),
+            address: Address::InProcess,
+            principal: None,
+            framed: false,
+        })
+    }
+
+    /// Attaches already split halves, with a principal if the caller identified the peer itself.
+    pub fn attach(&self, incoming: Incoming) -> Result<(), Error> {
+        match self.incoming_tx.send(Ok(incoming)) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::new(ErrorKind::NotConnected, "Server has stopped.")),
+        }
+    }
+
+    /// Shuts the server down like SIGINT or SIGTERM would.
+    pub fn shutdown(&self) {
+        let _ = self.shutdown_tx.send(());
+    }
+}
+
+fn transform_connection(data: std::io::Result<Incoming>) -> Event {
     match data {
-        Ok(stream) => Event::Connection(stream),
+        Ok(incoming) => Event::Connection(incoming),
         Err(e) => Event::ServerError(e),
     }
 }
 
-fn transform_packet(
-    connection_id: ConnectionId,
-    packet: Result<Packet<StringKey>, Error>,
-) -> Event {
-    match packet {
-        Ok(packet) => Event::Packet((connection_id, packet)),
-        Err(e) => Event::ConnectionError((connection_id, e)),
+impl Drop for Server {
+    fn drop(&mut self) {
+        // The read-loops hold the state, so it would keep their connections open otherwise.
+        if let Ok(mut connections) = self.state.connections.write() {
+            connections.clear();
+        }
     }
 }
 
-fn transform_point_update(point: StringKey, value: Value) -> Event {
-    Event::PointUpdate((
-        point,
-        value,
-    ))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{request, server, spawn_server, PCT};
+
+    #[tokio::test]
+    async fn in_process_connections() {
+        let server = spawn_server(server());
+
+        let (mut producer, remote) = tokio::io::duplex(1024);
+        server.handle.connect(remote).unwrap();
+
+        let (mut consumer, remote) = tokio::io::duplex(1024);
+        server.handle.connect(remote).unwrap();
+
+        let key = StringKey::new("plant/speed").unwrap();
+
+        let schema = PCT::RegisterSchema {
+            schema: String::from("plant { - speed: i32 }"),
+        };
+        assert_eq!(request(&mut producer, schema).await, PCT::Ok {});
+
+        let subscribe = PCT::Subscribe {
+            id: StringKey::new("plant/*").unwrap(),
+        };
+        assert_eq!(request(&mut consumer, subscribe).await, PCT::Ok {});
+
+        let update = PCT::Update {
+            id: key.clone(),
+            new_value: Value::I32(42),
+        };
+        assert_eq!(request(&mut producer, update).await, PCT::Ok {});
+
+        assert_eq!(
+            PCT::read_from(&mut consumer).await.unwrap(),
+            PCT::Update {
+                id: key.clone(),
+                new_value: Value::I32(42),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn updates_of_a_point_stay_in_order() {
+        let server = spawn_server(server());
+        let handle = &server.handle;
+
+        let (mut producer, remote) = tokio::io::duplex(1024);
+        handle.connect(remote).unwrap();
+
+        // Small enough for the consumer to fall behind, so updates are coalesced.
+        let (mut consumer, remote) = tokio::io::duplex(64);
+        handle.connect(remote).unwrap();
+
+        let key = StringKey::new("plant/speed").unwrap();
+
+        let schema = PCT::RegisterSchema {
+            schema: String::from("plant { - speed: i32 }"),
+        };
+        assert_eq!(request(&mut producer, schema).await, PCT::Ok {});
+
+        let subscribe = PCT::Subscribe {
+            id: StringKey::new("plant/*").unwrap(),
+        };
+        assert_eq!(request(&mut consumer, subscribe).await, PCT::Ok {});
+
+        for i in 0..1000 {
+            let update = PCT::Update {
+                id: key.clone(),
+                new_value: Value::I32(i),
+            };
+            assert_eq!(request(&mut producer, update).await, PCT::Ok {});
+        }
+
+        let mut last = -1;
+
+        while last < 999 {
+            match PCT::read_from(&mut consumer).await.unwrap() {
+                PCT::Update {
+                    new_value: Value::I32(i),
+                    ..
+                } => {
+                    assert!(i > last, "{} arrived after {}", i, last);
+                    last = i;
+                }
+                packet => panic!("Unexpected packet {:?}", packet),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn writers_run_in_parallel() {
+        let server = spawn_server(server());
+        let handle = &server.handle;
+
+        let (mut consumer, remote) = tokio::io::duplex(1024);
+        handle.connect(remote).unwrap();
+
+        let mut producers = vec![];
+
+        for _ in 0..4 {
+            let (producer, remote) = tokio::io::duplex(1024);
+            handle.connect(remote).unwrap();
+            producers.push(producer);
+        }
+
+        let schema = PCT::RegisterSchema {
+            schema: String::from("plant { - p0: i32 - p1: i32 - p2: i32 - p3: i32 }"),
+        };
+        assert_eq!(request(&mut consumer, schema).await, PCT::Ok {});
+
+        let subscribe = PCT::Subscribe {
+            id: StringKey::new("plant/*").unwrap(),
+        };
+        assert_eq!(request(&mut consumer, subscribe).await, PCT::Ok {});
+
+        let writes = producers
+            .iter_mut()
+            .enumerate()
+            .map(|(n, producer)| async move {
+                let key = StringKey::new(&format!("plant/p{}", n)).unwrap();
+
+                for i in 0..200 {
+                    let update = PCT::Update {
+                        id: key.clone(),
+                        new_value: Value::I32(i),
+                    };
+                    assert_eq!(request(producer, update).await, PCT::Ok {});
+                }
+            });
+
+        let reads = async {
+            let mut last = [-1; 4];
+
+            while last.iter().any(|&i| i < 199) {
+                match PCT::read_from(&mut consumer).await.unwrap() {
+                    PCT::Update {
+                        id,
+                        new_value: Value::I32(i),
+                    } => {
+                        let n = id.as_str().strip_prefix("plant/p").unwrap();
+                        let last = &mut last[n.parse::<usize>().unwrap()];
+
+                        assert!(i > *last, "{} arrived after {}", i, last);
+                        *last = i;
+                    }
+                    packet => panic!("Unexpected packet {:?}", packet),
+                }
+            }
+        };
+
+        tokio::join!(futures_util::future::join_all(writes), reads);
+    }
+
+    #[tokio::test]
+    async fn shutdown_notifies_connections() {
+        let (_dir, mut server) = server();
+        let handle = server.handle();
+
+        let (mut client, remote) = tokio::io::duplex(1024);
+        handle.connect(remote).unwrap();
+
+        let clients = async {
+            let schema = PCT::RegisterSchema {
+                schema: String::from("plant { - speed: i32 }"),
+            };
+            assert_eq!(request(&mut client, schema).await, PCT::Ok {});
+
+            handle.shutdown();
+
+            assert_eq!(PCT::read_from(&mut client).await.unwrap(), PCT::Shutdown {});
+            assert!(PCT::read_from(&mut client).await.is_err());
+        };
+
+        let (result, _) = tokio::join!(server.run(), clients);
+
+        assert!(result.is_ok());
+        assert!(handle.connect(tokio::io::duplex(64).0).is_err());
+    }
 }
@@ -0,0 +1,104 @@
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use std::convert::Infallible;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+
+use crate::gateway::{next_stream_id, Action, Gateway};
+use crate::rest::{authorization, Rejected};
+use crate::values;
+
+#[derive(Debug, Deserialize)]
+pub struct StreamQuery {
+    query: Option<String>,
+}
+
+/// `GET /stream?query=glob`, every matching update is sent as an `update`-event carrying the
+/// same JSON as `GET /points/{ns}/{point}`.
+pub async fn stream(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+    Query(query): Query<StreamQuery>,
+) -> Result<Response, Rejected> {
+    let session = gateway.authenticate_header(authorization(&headers)).await?;
+    let (tx, rx) = gateway.update_channel();
+
+    let subscribe = Action::Subscribe {
+        stream: next_stream_id(),
+        pattern: query.query.unwrap_or_else(|| String::from("**")),
+        tx,
+    };
+
+    gateway.request(&session, subscribe).await?;
+
+    let events = ReceiverStream::new(rx).map(|(id, value)| {
+        let data = values::point_json(id.as_str(), Some(&value));
+
+        Ok::<_, Infallible>(Event::default().event("update").data(data.to_string()))
+    });
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::listener::Listener;
+    use crate::testing::{http, producer, request, secured, spawn_server, update, PCT};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn event_streams_get_updates() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = spawn_server(secured(vec![Listener::Http(listener)]));
+
+        let mut producer = producer(&server.handle).await;
+
+        let stream = "GET /stream?query=plant/**";
+        assert_eq!(http(address, stream, None, None).await.0, 401);
+
+        let mut consumer = TcpStream::connect(address).await.unwrap();
+        let head = format!(
+            "{} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer dashboard-token\r\n\r\n",
+            stream
+        );
+        consumer.write_all(head.as_bytes()).await.unwrap();
+
+        // The stream is subscribed once its head arrived.
+        let mut received = String::new();
+        let mut buffer = [0; 1024];
+
+        while !received.contains("\r\n\r\n") {
+            let read = consumer.read(&mut buffer).await.unwrap();
+            received.push_str(std::str::from_utf8(&buffer[..read]).unwrap());
+        }
+
+        assert!(received.starts_with("HTTP/1.1 200 OK"));
+        assert!(received.contains("text/event-stream"));
+
+        // The dashboard may not read the second line, so it only gets the first.
+        assert_eq!(
+            request(&mut producer, update("plant/line2/speed", 1)).await,
+            PCT::Ok {}
+        );
+        assert_eq!(
+            request(&mut producer, update("plant/line1/speed", 2)).await,
+            PCT::Ok {}
+        );
+
+        while !received.contains("plant/line1/speed") {
+            let read = consumer.read(&mut buffer).await.unwrap();
+            received.push_str(std::str::from_utf8(&buffer[..read]).unwrap());
+        }
+
+        assert!(received.contains("event: update"));
+        assert!(received.contains(r#""value":2"#));
+        assert!(!received.contains("plant/line2/speed"));
+    }
+}
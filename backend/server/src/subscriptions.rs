@@ -0,0 +1,329 @@
+use schema::QuerySet;
+use std::collections::{HashMap, HashSet};
+
+use crate::connection::ConnectionId;
+use crate::gateway::StreamId;
+
+/// A connection or a stream of another front-end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Subscriber {
+    Connection(ConnectionId),
+    Stream(StreamId),
+}
+
+/// One segment of a pattern, between two slashes.
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// `*`, any single segment.
+    Any,
+    /// `**`, any number of segments.
+    AnyDepth,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    literals: HashMap<String, Node>,
+    any: Option<Box<Node>>,
+    any_depth: Option<Box<Node>>,
+    subscribers: HashSet<Subscriber>,
+}
+
+impl Node {
+    fn insert(&mut self, segments: &[Segment], subscriber: Subscriber) {
+        let (first, rest) = match segments.split_first() {
+            Some(split) => split,
+            None => {
+                self.subscribers.insert(subscriber);
+                return;
+            }
+        };
+
+        let child = match first {
+            Segment::Literal(literal) => self.literals.entry(literal.clone()).or_default(),
+            Segment::Any => self.any.get_or_insert_with(Default::default),
+            Segment::AnyDepth => self.any_depth.get_or_insert_with(Default::default),
+        };
+
+        child.insert(rest, subscriber);
+    }
+
+    /// Returns true once the node is empty and can be pruned.
+    fn remove(&mut self, segments: &[Segment], subscriber: &Subscriber) -> bool {
+        match segments.split_first() {
+            None => {
+                self.subscribers.remove(subscriber);
+            }
+            Some((Segment::Literal(literal), rest)) => {
+                if let Some(child) = self.literals.get_mut(literal) {
+                    if child.remove(rest, subscriber) {
+                        self.literals.remove(literal);
+                    }
+                }
+            }
+            Some((Segment::Any, rest)) => {
+                if let Some(child) = &mut self.any {
+                    if child.remove(rest, subscriber) {
+                        self.any = None;
+                    }
+                }
+            }
+            Some((Segment::AnyDepth, rest)) => {
+                if let Some(child) = &mut self.any_depth {
+                    if child.remove(rest, subscriber) {
+                        self.any_depth = None;
+                    }
+                }
+            }
+        }
+
+        self.subscribers.is_empty()
+            && self.literals.is_empty()
+            && self.any.is_none()
+            && self.any_depth.is_none()
+    }
+
+    fn collect(&self, segments: &[&str], found: &mut HashSet<Subscriber>) {
+        if let Some(any_depth) = &self.any_depth {
+            for skipped in 0..=segments.len() {
+                any_depth.collect(&segments[skipped..], found);
+            }
+        }
+
+        match segments.split_first() {
+            None => found.extend(&self.subscribers),
+            Some((first, rest)) => {
+                if let Some(child) = self.literals.get(*first) {
+                    child.collect(rest, found);
+                }
+
+                if let Some(any) = &self.any {
+                    any.collect(rest, found);
+                }
+            }
+        }
+    }
+}
+
+/// Maps a point to the subscribers with a matching pattern, so an update only visits those.
+/// Patterns without globs are looked up by key, those made of literal segments, `*` and `**` in a
+/// tree of segments. Others, like `plant/line?/*`, are matched one by one.
+///
+/// The result may contain subscribers whose pattern doesn't match exactly, e.g. `plant/**` for
+/// `plant`, so they are checked against their `QuerySet` before an update is sent.
+#[derive(Debug, Default)]
+pub struct SubscriptionIndex {
+    exact: HashMap<String, HashSet<Subscriber>>,
+    tree: Node,
+    others: HashMap<String, (QuerySet, HashSet<Subscriber>)>,
+    patterns: HashMap<Subscriber, HashSet<String>>,
+}
+
+impl SubscriptionIndex {
+    pub fn new() -> Self {
+        SubscriptionIndex::default()
+    }
+
+    /// The pattern has to be valid, i.e. accepted by `QuerySet::insert_point` before.
+    pub fn insert(&mut self, pattern: &str, subscriber: Subscriber) {
+        let added = self
+            .patterns
+            .entry(subscriber)
+            .or_default()
+            .insert(String::from(pattern));
+
+        if !added {
+            return;
+        }
+
+        match parse(pattern) {
+            Some(segments) => match literal(&segments) {
+                Some(key) => {
+                    self.exact.entry(key).or_default().insert(subscriber);
+                }
+                None => self.tree.insert(&segments, subscriber),
+            },
+            None => {
+                if let Ok(set) = QuerySet::single(pattern) {
+                    let (_, subscribers) = self
+                        .others
+                        .entry(String::from(pattern))
+                        .or_insert_with(|| (set, HashSet::new()));
+
+                    subscribers.insert(subscriber);
+                }
+            }
+        }
+    }
+
+    pub fn remove(&mut self, pattern: &str, subscriber: Subscriber) {
+        let removed = match self.patterns.get_mut(&subscriber) {
+            Some(patterns) => patterns.remove(pattern),
+            None => false,
+        };
+
+        if !removed {
+            return;
+        }
+
+        if self.patterns[&subscriber].is_empty() {
+            self.patterns.remove(&subscriber);
+        }
+
+        self.unindex(pattern, &subscriber);
+    }
+
+    /// Removes all patterns of a subscriber that went away.
+    pub fn remove_subscriber(&mut self, subscriber: Subscriber) {
+        for pattern in self.patterns.remove(&subscriber).unwrap_or_default() {
+            self.unindex(&pattern, &subscriber);
+        }
+    }
+
+    /// Subscribers that may want an update of the point, each once.
+    pub fn lookup(&self, key: &str) -> HashSet<Subscriber> {
+        let key = key.to_lowercase();
+        let mut found = HashSet::new();
+
+        if let Some(subscribers) = self.exact.get(&key) {
+            found.extend(subscribers);
+        }
+
+        let segments: Vec<&str> = key.split('/').collect();
+        self.tree.collect(&segments, &mut found);
+
+        for (set, subscribers) in self.others.values() {
+            if set.matches(&key) {
+                found.extend(subscribers);
+            }
+        }
+
+        found
+    }
+
+    fn unindex(&mut self, pattern: &str, subscriber: &Subscriber) {
+        match parse(pattern) {
+            Some(segments) => match literal(&segments) {
+                Some(key) => {
+                    if let Some(subscribers) = self.exact.get_mut(&key) {
+                        subscribers.remove(subscriber);
+
+                        if subscribers.is_empty() {
+                            self.exact.remove(&key);
+                        }
+                    }
+                }
+                None => {
+                    self.tree.remove(&segments, subscriber);
+                }
+            },
+            None => {
+                if let Some((_, subscribers)) = self.others.get_mut(pattern) {
+                    subscribers.remove(subscriber);
+
+                    if subscribers.is_empty() {
+                        self.others.remove(pattern);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns `None` if a segment contains other globs than a whole `*` or `**`. Escaped characters
+/// are taken literally, like `gateway::escape_pattern` produces them.
+fn parse(pattern: &str) -> Option<Vec<Segment>> {
+    pattern
+        .split('/')
+        .map(|segment| match segment {
+            "*" => Some(Segment::Any),
+            "**" => Some(Segment::AnyDepth),
+            _ => {
+                let mut literal = String::with_capacity(segment.len());
+                let mut chars = segment.chars();
+
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => literal.extend(chars.next()?.to_lowercase()),
+                        '*' | '?' | '[' | ']' | '{' | '}' => return None,
+                        c => literal.extend(c.to_lowercase()),
+                    }
+                }
+
+                Some(Segment::Literal(literal))
+            }
+        })
+        .collect()
+}
+
+/// The key a pattern matches, if it only has literal segments.
+fn literal(segments: &[Segment]) -> Option<String> {
+    let literals: Option<Vec<&str>> = segments
+        .iter()
+        .map(|segment| match segment {
+            Segment::Literal(literal) => Some(literal.as_str()),
+            _ => None,
+        })
+        .collect();
+
+    literals.map(|literals| literals.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(index: &SubscriptionIndex, key: &str) -> Vec<StreamId> {
+        let mut ids: Vec<StreamId> = index
+            .lookup(key)
+            .into_iter()
+            .map(|subscriber| match subscriber {
+                Subscriber::Stream(id) => id,
+                Subscriber::Connection(_) => unreachable!(),
+            })
+            .collect();
+
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn finds_matching_subscribers() {
+        let mut index = SubscriptionIndex::new();
+
+        index.insert("plant/line1/speed", Subscriber::Stream(0));
+        index.insert("plant/*/speed", Subscriber::Stream(1));
+        index.insert("plant/**", Subscriber::Stream(2));
+        index.insert("**/speed", Subscriber::Stream(3));
+        index.insert("plant/line?/*", Subscriber::Stream(4));
+        index.insert("Plant/Line\\*/speed", Subscriber::Stream(5));
+
+        assert_eq!(found(&index, "plant/line1/speed"), vec![0, 1, 2, 3, 4]);
+        assert_eq!(found(&index, "plant/line2/speed"), vec![1, 2, 3, 4]);
+        assert_eq!(found(&index, "plant/line*/speed"), vec![1, 2, 3, 4, 5]);
+        assert_eq!(found(&index, "plant/line1/nested/speed"), vec![2, 3]);
+        assert_eq!(found(&index, "speed"), vec![3]);
+        assert_eq!(found(&index, "other/temp"), Vec::<StreamId>::new());
+    }
+
+    #[test]
+    fn removes_subscribers() {
+        let mut index = SubscriptionIndex::new();
+
+        index.insert("plant/*", Subscriber::Stream(0));
+        index.insert("plant/speed", Subscriber::Stream(0));
+        index.insert("plant/*", Subscriber::Stream(1));
+        index.insert("plant/spe?d", Subscriber::Stream(1));
+
+        index.remove("plant/*", Subscriber::Stream(0));
+        assert_eq!(found(&index, "plant/speed"), vec![0, 1]);
+        assert_eq!(found(&index, "plant/temp"), vec![1]);
+
+        index.remove_subscriber(Subscriber::Stream(1));
+        assert_eq!(found(&index, "plant/speed"), vec![0]);
+
+        index.remove_subscriber(Subscriber::Stream(0));
+        assert!(index.exact.is_empty() && index.others.is_empty() && index.patterns.is_empty());
+        assert!(index.tree.literals.is_empty());
+    }
+}
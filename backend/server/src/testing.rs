@@ -0,0 +1,203 @@
+//! Fixtures shared by the tests of the server and its front-ends.
+
+use protocol::{Packet, StringKey, Value};
+use std::net::SocketAddr;
+use std::thread::JoinHandle;
+use store::rocksdb::create_rocksdb;
+use tempfile::TempDir;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+use crate::acl::Acl;
+use crate::auth::Credentials;
+use crate::config::Limits;
+use crate::listener::Listener;
+use crate::server::{Server, ServerHandle};
+
+#[allow(clippy::upper_case_acronyms)]
+pub type PCT = Packet<StringKey>;
+
+pub const CREDENTIALS: &str = "
+    [[tokens]]
+    principal = \"producer\"
+    token = \"producer-token\"
+
+    [[tokens]]
+    principal = \"dashboard\"
+    token = \"dashboard-token\"
+";
+
+/// Producers may register and write the plant, dashboards may only read its first line.
+pub const ACL: &str = "
+    [principals.producer]
+    register = [\"**\"]
+    write = [\"plant/**\"]
+
+    [principals.dashboard]
+    subscribe = [\"plant/**\"]
+    read = [\"plant/line1/*\"]
+";
+
+pub const SCHEMA: &str = "
+    plant {
+        line1 { - speed: i32 }
+        line2 { - speed: i32 }
+    }
+
+    office { - temp: i32 }
+";
+
+/// A server without credentials or acl. Its store is deleted with the directory.
+pub fn server() -> (TempDir, Server) {
+    let dir = tempfile::tempdir().unwrap();
+    let store = create_rocksdb(dir.path()).unwrap();
+    let server = Server::new(store, vec![], None, None, None, Limits::default());
+
+    (dir, server)
+}
+
+/// Like `server`, with `CREDENTIALS` and `ACL`.
+pub fn secured(listeners: Vec<Listener>) -> (TempDir, Server) {
+    let dir = tempfile::tempdir().unwrap();
+    let store = create_rocksdb(dir.path()).unwrap();
+    let credentials = Credentials::parse(CREDENTIALS).unwrap();
+    let acl = Acl::parse(ACL).unwrap();
+    let server = Server::new(
+        store,
+        listeners,
+        None,
+        Some(credentials),
+        Some(acl),
+        Limits::default(),
+    );
+
+    (dir, server)
+}
+
+/// A server running on a thread and runtime of its own, stopped when dropped.
+pub struct Spawned {
+    pub handle: ServerHandle,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    _dir: TempDir,
+}
+
+/// Runs the server until the returned `Spawned` is dropped, so tests only drive their clients.
+pub fn spawn_server((dir, mut server): (TempDir, Server)) -> Spawned {
+    let handle = server.handle();
+    let (stop, stopped) = oneshot::channel::<()>();
+
+    let thread = std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(4)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            tokio::select! {
+                _ = server.run() => panic!("Server stopped."),
+                _ = stopped => {}
+            }
+        });
+    });
+
+    Spawned {
+        handle,
+        stop: Some(stop),
+        thread: Some(thread),
+        _dir: dir,
+    }
+}
+
+impl Drop for Spawned {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+
+        // The store has to be closed before its directory is deleted.
+        if let Some(thread) = self.thread.take() {
+            if let Err(panic) = thread.join() {
+                if !std::thread::panicking() {
+                    std::panic::resume_unwind(panic);
+                }
+            }
+        }
+    }
+}
+
+/// Attaches a producer that is authenticated and registered `SCHEMA`.
+pub async fn producer(handle: &ServerHandle) -> DuplexStream {
+    let (mut producer, remote) = tokio::io::duplex(1024);
+    handle.connect(remote).unwrap();
+
+    let authenticate = PCT::Authenticate {
+        user: String::new(),
+        secret: String::from("producer-token"),
+    };
+    assert_eq!(request(&mut producer, authenticate).await, PCT::Ok {});
+
+    let schema = PCT::RegisterSchema {
+        schema: String::from(SCHEMA),
+    };
+    assert_eq!(request(&mut producer, schema).await, PCT::Ok {});
+
+    producer
+}
+
+pub fn update(id: &str, value: i32) -> PCT {
+    PCT::Update {
+        id: StringKey::new(id).unwrap(),
+        new_value: Value::I32(value),
+    }
+}
+
+/// Sends an HTTP-request on a connection of its own and returns the status and body.
+pub async fn http(
+    address: SocketAddr,
+    request: &str,
+    token: Option<&str>,
+    body: Option<&str>,
+) -> (u16, String) {
+    let mut head = format!(
+        "{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n",
+        request
+    );
+
+    if let Some(token) = token {
+        head.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+
+    let body = body.unwrap_or_default();
+
+    if !body.is_empty() {
+        head.push_str("Content-Type: application/json\r\n");
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(b"\r\n").await.unwrap();
+    stream.write_all(body.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    let status = response[9..12].parse().unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+
+    (status, String::from(body))
+}
+
+/// Sends a packet and waits for the next one.
+pub async fn request<S>(stream: &mut S, packet: PCT) -> PCT
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    packet.write_to(stream).await.unwrap();
+    stream.flush().await.unwrap();
+
+    PCT::read_from(stream).await.unwrap()
+}
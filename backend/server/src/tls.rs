@@ -0,0 +1,354 @@
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::auth::Principal;
+use crate::listener::ACCEPT_BACKOFF;
+
+/// Builds an acceptor from PEM-files. With a `client_ca` the server asks for client-certificates
+/// and verifies them against it, but clients without one may still authenticate with a packet.
+pub fn load_acceptor(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<TlsAcceptor, Error> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", cert.display(), e)))?;
+
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", key.display(), e)))?;
+
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+
+            for ca in CertificateDer::pem_file_iter(path).map_err(|e| {
+                Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+            })? {
+                let ca = ca.map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+                roots
+                    .add(ca)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .allow_unauthenticated()
+                .build()
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Runs the handshake and maps a verified client-certificate to a principal. Clients that don't
+/// finish the handshake within `timeout` are dropped, like those that don't authenticate.
+pub async fn accept(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+    timeout: Duration,
+) -> Result<(TlsStream<TcpStream>, Option<Principal>), Error> {
+    let stream = tokio::time::timeout(timeout, acceptor.accept(stream))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "TLS-handshake timed out."))??;
+
+    let principal = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(principal_from_cert);
+
+    Ok((stream, principal))
+}
+
+type Handshake = Result<(TlsStream<TcpStream>, SocketAddr), (SocketAddr, Error)>;
+
+/// Terminates TLS for the HTTP front-ends, see `rest::serve_router`. Handshakes run in their own
+/// tasks so a slow client can't hold up the others. Clients authenticate with their
+/// `Authorization` header, a client-certificate is only verified.
+pub struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    timeout: Duration,
+    handshakes: JoinSet<Handshake>,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor, timeout: Duration) -> Self {
+        TlsListener {
+            listener,
+            acceptor,
+            timeout,
+            handshakes: JoinSet::new(),
+        }
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, address)) => {
+                        let acceptor = self.acceptor.clone();
+                        let timeout = self.timeout;
+
+                        self.handshakes.spawn(async move {
+                            match accept(&acceptor, stream, timeout).await {
+                                Ok((stream, _)) => Ok((stream, address)),
+                                Err(e) => Err((address, e)),
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        warn!(error = %e, "Could not accept HTTP-client");
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    }
+                },
+                Some(handshake) = self.handshakes.join_next() => match handshake {
+                    Ok(Ok(accepted)) => return accepted,
+                    Ok(Err((address, e))) => {
+                        info!(peer = %address, reason = %e, "HTTP-handshake failed")
+                    }
+                    Err(_) => {}
+                },
+            }
+        }
+    }
+
+    fn local_addr(&self) -> Result<Self::Addr, Error> {
+        self.listener.local_addr()
+    }
+}
+
+/// The principal of a client-certificate is the common name of its subject.
+fn principal_from_cert(cert: &CertificateDer) -> Option<Principal> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+
+    Some(Principal::new(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AUTH_TIMEOUT;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use std::convert::TryFrom;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::TlsConnector;
+
+    struct Pki {
+        dir: tempfile::TempDir,
+        ca: CertificateDer<'static>,
+        client_cert: CertificateDer<'static>,
+        client_key: PrivateKeyDer<'static>,
+    }
+
+    /// Self-signed CA that signs a server-certificate for localhost and a client-certificate.
+    fn generate() -> Pki {
+        let dir = tempfile::tempdir().unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "rmber test ca");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server_params = CertificateParams::new(vec![String::from("localhost")]).unwrap();
+        let server = server_params.signed_by(&server_key, &ca, &ca_key).unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params = CertificateParams::new(vec![]).unwrap();
+        client_params
+            .distinguished_name
+            .push(DnType::CommonName, "producer");
+        let client = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        std::fs::write(dir.path().join("ca.pem"), ca.pem()).unwrap();
+        std::fs::write(dir.path().join("cert.pem"), server.pem()).unwrap();
+        std::fs::write(dir.path().join("key.pem"), server_key.serialize_pem()).unwrap();
+
+        Pki {
+            dir,
+            ca: ca.der().clone(),
+            client_cert: client.der().clone(),
+            client_key: PrivateKeyDer::try_from(client_key.serialize_der()).unwrap(),
+        }
+    }
+
+    async fn handshake(acceptor: TlsAcceptor, client: ClientConfig) -> Option<Principal> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let connector = TlsConnector::from(Arc::new(client));
+            let name = ServerName::try_from("localhost").unwrap();
+            let mut stream = connector.connect(name, stream).await.unwrap();
+
+            stream.write_u8(42).await.unwrap();
+            stream.flush().await.unwrap();
+            stream.read_u8().await.unwrap()
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut stream, principal) = accept(&acceptor, stream, AUTH_TIMEOUT).await.unwrap();
+
+        let byte = stream.read_u8().await.unwrap();
+        stream.write_u8(byte + 1).await.unwrap();
+        stream.flush().await.unwrap();
+
+        assert_eq!(client.await.unwrap(), 43);
+
+        principal
+    }
+
+    fn client_config(
+        pki: &Pki,
+    ) -> tokio_rustls::rustls::ConfigBuilder<
+        ClientConfig,
+        tokio_rustls::rustls::client::WantsClientCert,
+    > {
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca.clone()).unwrap();
+
+        ClientConfig::builder().with_root_certificates(roots)
+    }
+
+    #[tokio::test]
+    async fn tls_without_client_certificate() {
+        let pki = generate();
+        let acceptor = load_acceptor(
+            &pki.dir.path().join("cert.pem"),
+            &pki.dir.path().join("key.pem"),
+            None,
+        )
+        .unwrap();
+
+        let principal = handshake(acceptor, client_config(&pki).with_no_client_auth()).await;
+
+        assert_eq!(principal, None);
+    }
+
+    #[tokio::test]
+    async fn client_certificate_maps_to_principal() {
+        let pki = generate();
+        let ca = pki.dir.path().join("ca.pem");
+        let acceptor = load_acceptor(
+            &pki.dir.path().join("cert.pem"),
+            &pki.dir.path().join("key.pem"),
+            Some(&ca),
+        )
+        .unwrap();
+
+        let client = client_config(&pki)
+            .with_client_auth_cert(vec![pki.client_cert.clone()], pki.client_key.clone_key())
+            .unwrap();
+
+        let principal = handshake(acceptor.clone(), client).await;
+        assert_eq!(principal, Some(Principal::new("producer")));
+
+        // Client-certificates are optional, those clients authenticate with a packet instead.
+        let principal = handshake(acceptor, client_config(&pki).with_no_client_auth()).await;
+        assert_eq!(principal, None);
+    }
+
+    #[tokio::test]
+    async fn http_is_served_over_tls() {
+        let pki = generate();
+        let acceptor = load_acceptor(
+            &pki.dir.path().join("cert.pem"),
+            &pki.dir.path().join("key.pem"),
+            None,
+        )
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = axum::Router::new().route("/", axum::routing::get(|| async { "served" }));
+
+        tokio::spawn(async move {
+            axum::serve(TlsListener::new(listener, acceptor, AUTH_TIMEOUT), router)
+                .await
+                .unwrap()
+        });
+
+        // Plaintext clients fail their handshake without stopping the listener.
+        let mut plain = TcpStream::connect(address).await.unwrap();
+        plain.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = vec![];
+        let _ = plain.read_to_end(&mut response).await;
+        assert!(!String::from_utf8_lossy(&response).contains("served"));
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let connector = TlsConnector::from(Arc::new(client_config(&pki).with_no_client_auth()));
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(name, stream).await.unwrap();
+
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("served"));
+    }
+
+    #[tokio::test]
+    async fn silent_clients_time_out() {
+        let pki = generate();
+        let acceptor = load_acceptor(
+            &pki.dir.path().join("cert.pem"),
+            &pki.dir.path().join("key.pem"),
+            None,
+        )
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let error = accept(&acceptor, stream, Duration::from_millis(50))
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+}
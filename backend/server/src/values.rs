@@ -0,0 +1,167 @@
+use protocol::Value;
+use schema::PointType;
+use serde_json::json;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use store::to_point_type;
+
+/// Points that were never written have a `null` value and type.
+pub fn point_json(id: &str, value: Option<&Value>) -> serde_json::Value {
+    json!({
+        "id": id,
+        "type": value.map(|v| to_point_type(v).as_str()),
+        "value": value.map(to_json),
+    })
+}
+
+/// Blobs are hex-encoded, non-finite floats become `null`.
+pub fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Boolean(v) => json!(v),
+        Value::Blob(v) => json!(hex::encode(v)),
+        Value::String(v) => json!(v),
+        Value::U8(v) => json!(v),
+        Value::I8(v) => json!(v),
+        Value::U16(v) => json!(v),
+        Value::I16(v) => json!(v),
+        Value::U32(v) => json!(v),
+        Value::I32(v) => json!(v),
+        Value::U64(v) => json!(v),
+        Value::I64(v) => json!(v),
+        Value::F32(v) => json!(v),
+        Value::F64(v) => json!(v),
+    }
+}
+
+/// JSON has no integer-widths, so the value takes the first of the point's types it fits into,
+/// in the order of `PointType::ALL`.
+pub fn from_json(json: &serde_json::Value, types: &HashSet<PointType>) -> Option<Value> {
+    PointType::ALL
+        .iter()
+        .filter(|t| types.contains(t))
+        .find_map(|t| convert(json, *t))
+}
+
+pub fn convert(json: &serde_json::Value, point_type: PointType) -> Option<Value> {
+    let value = match point_type {
+        PointType::Boolean => Value::Boolean(json.as_bool()?),
+        PointType::Blob => Value::Blob(hex::decode(json.as_str()?).ok()?),
+        PointType::String => Value::String(json.as_str()?.to_string()),
+        PointType::U8 => Value::U8(u8::try_from(json.as_u64()?).ok()?),
+        PointType::I8 => Value::I8(i8::try_from(json.as_i64()?).ok()?),
+        PointType::U16 => Value::U16(u16::try_from(json.as_u64()?).ok()?),
+        PointType::I16 => Value::I16(i16::try_from(json.as_i64()?).ok()?),
+        PointType::U32 => Value::U32(u32::try_from(json.as_u64()?).ok()?),
+        PointType::I32 => Value::I32(i32::try_from(json.as_i64()?).ok()?),
+        PointType::U64 => Value::U64(json.as_u64()?),
+        PointType::I64 => Value::I64(json.as_i64()?),
+        PointType::F32 => Value::F32(json.as_f64()? as f32),
+        PointType::F64 => Value::F64(json.as_f64()?),
+    };
+
+    Some(value)
+}
+
+/// Numbers and booleans as text, strings as UTF-8 and blobs as-is.
+pub fn to_text(value: &Value) -> Vec<u8> {
+    match value {
+        Value::Blob(v) => v.clone(),
+        Value::String(v) => v.clone().into_bytes(),
+        Value::Boolean(v) => v.to_string().into_bytes(),
+        Value::U8(v) => v.to_string().into_bytes(),
+        Value::I8(v) => v.to_string().into_bytes(),
+        Value::U16(v) => v.to_string().into_bytes(),
+        Value::I16(v) => v.to_string().into_bytes(),
+        Value::U32(v) => v.to_string().into_bytes(),
+        Value::I32(v) => v.to_string().into_bytes(),
+        Value::U64(v) => v.to_string().into_bytes(),
+        Value::I64(v) => v.to_string().into_bytes(),
+        Value::F32(v) => v.to_string().into_bytes(),
+        Value::F64(v) => v.to_string().into_bytes(),
+    }
+}
+
+/// The inverse of `to_text`, taking the first of the point's types that parses like `from_json`.
+/// Strings and blobs come last, so `42` is only a string if the point can't hold a number.
+pub fn from_text(text: &[u8], types: &HashSet<PointType>) -> Option<Value> {
+    PointType::ALL
+        .iter()
+        .filter(|t| types.contains(t))
+        .find_map(|t| parse(text, *t))
+}
+
+pub fn parse(text: &[u8], point_type: PointType) -> Option<Value> {
+    if point_type == PointType::Blob {
+        return Some(Value::Blob(text.to_vec()));
+    }
+
+    let text = std::str::from_utf8(text).ok()?;
+
+    if point_type == PointType::String {
+        return Some(Value::String(text.to_string()));
+    }
+
+    let text = text.trim();
+
+    let value = match point_type {
+        PointType::Boolean => Value::Boolean(text.parse().ok()?),
+        PointType::U8 => Value::U8(text.parse().ok()?),
+        PointType::I8 => Value::I8(text.parse().ok()?),
+        PointType::U16 => Value::U16(text.parse().ok()?),
+        PointType::I16 => Value::I16(text.parse().ok()?),
+        PointType::U32 => Value::U32(text.parse().ok()?),
+        PointType::I32 => Value::I32(text.parse().ok()?),
+        PointType::U64 => Value::U64(text.parse().ok()?),
+        PointType::I64 => Value::I64(text.parse().ok()?),
+        PointType::F32 => Value::F32(text.parse().ok()?),
+        PointType::F64 => Value::F64(text.parse().ok()?),
+        PointType::String | PointType::Blob => unreachable!(),
+    };
+
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_roundtrip() {
+        let values = vec![
+            (Value::Boolean(true), PointType::Boolean),
+            (Value::Blob(vec![0xde, 0xad]), PointType::Blob),
+            (Value::String(String::from("test")), PointType::String),
+            (Value::I8(-8), PointType::I8),
+            (Value::U64(u64::MAX), PointType::U64),
+            (Value::F64(0.5), PointType::F64),
+        ];
+
+        for (value, point_type) in values {
+            assert_eq!(convert(&to_json(&value), point_type), Some(value));
+        }
+    }
+
+    #[test]
+    fn numbers_take_the_first_fitting_type() {
+        let types: HashSet<_> = vec![PointType::I32, PointType::U16].into_iter().collect();
+
+        assert_eq!(from_json(&json!(5), &types), Some(Value::U16(5)));
+        assert_eq!(from_json(&json!(-5), &types), Some(Value::I32(-5)));
+        assert_eq!(from_json(&json!(1.5), &types), None);
+        assert_eq!(from_json(&json!("5"), &types), None);
+    }
+
+    #[test]
+    fn text_takes_the_first_fitting_type() {
+        let types: HashSet<_> = vec![PointType::I32, PointType::String]
+            .into_iter()
+            .collect();
+
+        assert_eq!(from_text(b"-5", &types), Some(Value::I32(-5)));
+        assert_eq!(from_text(b"five", &types), Some(Value::String(String::from("five"))));
+
+        let value = Value::F64(0.25);
+        assert_eq!(parse(&to_text(&value), PointType::F64), Some(value));
+        assert_eq!(parse(b"maybe", PointType::Boolean), None);
+    }
+}
@@ -0,0 +1,266 @@
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{Sink, Stream, StreamExt};
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::connection::{ReadStream, WriteStream};
+
+/// Runs the websocket-handshake and returns the stream as byte-oriented halves. Binary frames are
+/// read back-to-back, and everything written between two flushes is sent as one binary frame.
+/// Websocket-connections don't batch their updates, see `Incoming::framed`, so every packet is
+/// flushed on its own and browsers receive exactly one packet per frame.
+pub async fn accept<S>(stream: S) -> Result<(ReadStream, WriteStream), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let stream = match tokio_tungstenite::accept_async(stream).await {
+        Ok(stream) => stream,
+        Err(e) => return Err(convert_err(e)),
+    };
+
+    let (sink, stream) = stream.split();
+
+    let read = WebSocketRead {
+        stream,
+        buffer: vec![],
+        position: 0,
+    };
+
+    let write = WebSocketWrite {
+        sink,
+        buffer: vec![],
+    };
+
+    Ok((Box::new(read), Box::new(write)))
+}
+
+struct WebSocketRead<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<S> AsyncRead for WebSocketRead<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        while self.position == self.buffer.len() {
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Ready(Some(Ok(Message::Binary(data)))) => {
+                    self.buffer = data;
+                    self.position = 0;
+                }
+                Poll::Ready(Some(Ok(Message::Text(_)))) => {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Only binary frames are supported.",
+                    )))
+                }
+                // Pings are answered by tungstenite itself.
+                Poll::Ready(Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_)))) => {}
+                // Reading nothing signals EOF.
+                Poll::Ready(Some(Ok(Message::Close(_)))) | Poll::Ready(None) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(convert_err(e))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let len = buf.remaining().min(self.buffer.len() - self.position);
+        let start = self.position;
+
+        buf.put_slice(&self.buffer[start..start + len]);
+        self.position += len;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct WebSocketWrite<S> {
+    sink: SplitSink<WebSocketStream<S>, Message>,
+    buffer: Vec<u8>,
+}
+
+impl<S> AsyncWrite for WebSocketWrite<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        self.buffer.extend_from_slice(buf);
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if !self.buffer.is_empty() {
+            if let Err(e) = futures_util::ready!(Pin::new(&mut self.sink).poll_ready(cx)) {
+                return Poll::Ready(Err(convert_err(e)));
+            }
+
+            let frame = Message::Binary(std::mem::take(&mut self.buffer));
+
+            if let Err(e) = Pin::new(&mut self.sink).start_send(frame) {
+                return Poll::Ready(Err(convert_err(e)));
+            }
+        }
+
+        Pin::new(&mut self.sink).poll_flush(cx).map_err(convert_err)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        futures_util::ready!(self.as_mut().poll_flush(cx))?;
+
+        Pin::new(&mut self.sink).poll_close(cx).map_err(convert_err)
+    }
+}
+
+fn convert_err(err: tokio_tungstenite::tungstenite::Error) -> Error {
+    use tokio_tungstenite::tungstenite::Error as WsError;
+
+    match err {
+        WsError::Io(e) => e,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => {
+            Error::new(ErrorKind::ConnectionAborted, err)
+        }
+        _ => Error::new(ErrorKind::InvalidData, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Limits;
+    use crate::listener::Listener;
+    use crate::server::Server;
+    use crate::testing::{request, spawn_server, PCT};
+    use futures_util::SinkExt;
+    use protocol::{Packet, StringKey, Value};
+    use std::time::Duration;
+    use store::rocksdb::create_rocksdb;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// The next frame, which has to hold exactly one packet.
+    async fn frame(consumer: &mut WebSocketStream<TcpStream>) -> PCT {
+        let frame = match consumer.next().await.unwrap().unwrap() {
+            Message::Binary(frame) => frame,
+            message => panic!("Unexpected message {:?}", message),
+        };
+        let mut rest = &frame[..];
+        let packet = PCT::read_from(&mut rest).await.unwrap();
+
+        assert!(rest.is_empty(), "More than one packet in a frame");
+        packet
+    }
+
+    #[tokio::test]
+    async fn packets_map_to_binary_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let url = format!("ws://{}/", address);
+            let (mut ws, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+
+            // A packet split over two frames.
+            ws.send(Message::Binary(vec![1, 4, b't'])).await.unwrap();
+            ws.send(Message::Binary(vec![b'e', b's', b't']))
+                .await
+                .unwrap();
+
+            ws.next().await.unwrap().unwrap()
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut read, mut write) = accept(stream).await.unwrap();
+
+        let packet = Packet::<StringKey>::read_from(&mut read).await.unwrap();
+        assert_eq!(
+            packet,
+            Packet::Subscribe {
+                id: StringKey::new("test").unwrap()
+            }
+        );
+
+        Packet::<StringKey>::Ok {}
+            .write_to(&mut write)
+            .await
+            .unwrap();
+        write.flush().await.unwrap();
+
+        assert_eq!(client.await.unwrap(), Message::Binary(vec![5]));
+    }
+
+    #[tokio::test]
+    async fn updates_get_a_frame_each() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        // Long enough for both updates to end up in one batch, if websockets were batched.
+        let limits = Limits {
+            flush_interval: Duration::from_millis(500),
+            ..Limits::default()
+        };
+        let server = Server::new(
+            create_rocksdb(dir.path()).unwrap(),
+            vec![Listener::WebSocket(listener)],
+            None,
+            None,
+            None,
+            limits,
+        );
+        let server = spawn_server((dir, server));
+
+        let (mut producer, remote) = tokio::io::duplex(1024);
+        server.handle.connect(remote).unwrap();
+
+        // Updates of different points, which are never coalesced.
+        let update = |i| PCT::Update {
+            id: StringKey::new(&format!("plant/p{}", i)).unwrap(),
+            new_value: Value::I32(i),
+        };
+
+        let schema = PCT::RegisterSchema {
+            schema: String::from("plant { - p0: i32 - p1: i32 }"),
+        };
+        assert_eq!(request(&mut producer, schema).await, PCT::Ok {});
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let url = format!("ws://{}/", address);
+        let (mut consumer, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+
+        let mut subscribe = vec![];
+        PCT::Subscribe {
+            id: StringKey::new("plant/*").unwrap(),
+        }
+        .write_to(&mut subscribe)
+        .await
+        .unwrap();
+        consumer.send(Message::Binary(subscribe)).await.unwrap();
+
+        assert_eq!(frame(&mut consumer).await, PCT::Ok {});
+
+        for i in 0..2 {
+            assert_eq!(request(&mut producer, update(i)).await, PCT::Ok {});
+        }
+
+        for i in 0..2 {
+            assert_eq!(frame(&mut consumer).await, update(i));
+        }
+    }
+}
@@ -1,17 +1,24 @@
 use protocol::{Key, StringKey, Value};
-use schema::{Error, Point, PointType, QuerySet, Rule, Schema, parse};
+use schema::{Error, PointType, Rule, Schema, parse};
 use async_trait::async_trait;
+use metrics::{counter, histogram};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
 pub mod rocksdb;
 
-#[async_trait(?Send)]
-pub trait Store<TKey>
+/// Shared by all tasks of the server, so reads and writes may happen at the same time.
+#[async_trait]
+pub trait Store<TKey>: Send + Sync
 where
-    TKey: Key,
+    TKey: Key + Sync,
 {
-    async fn store_value(&mut self, key: &TKey, value: &Value) -> Result<(), std::io::Error>;
+    async fn store_value(&self, key: &TKey, value: &Value) -> Result<(), std::io::Error>;
 
-    async fn get_value(&mut self, key: &TKey) -> Option<Value>;
+    async fn get_value(&self, key: &TKey) -> Option<Value>;
+
+    /// Persists everything written so far, e.g. before the process exits.
+    async fn flush(&self) -> Result<(), std::io::Error>;
 }
 
 pub struct ValueStore<TStore>
@@ -19,7 +26,8 @@ where
     TStore: Store<StringKey>,
 {
     store: TStore,
-    schema: Schema,
+    /// Replaced as a whole when it is rebuilt, readers keep the one they started with.
+    schema: RwLock<Arc<Schema>>,
 }
 
 impl<TStore> ValueStore<TStore>
@@ -29,11 +37,16 @@ where
     pub fn new(store: TStore) -> Self {
         ValueStore {
             store,
-            schema: Schema::empty(),
+            schema: RwLock::new(Arc::new(Schema::empty())),
         }
     }
 
-    pub fn build_schema<TIter>(&mut self, source: TIter) -> Result<(), Error<Rule>>
+    /// The current schema, it doesn't change while it is used.
+    pub fn schema(&self) -> Arc<Schema> {
+        self.schema.read().unwrap().clone()
+    }
+
+    pub fn build_schema<TIter>(&self, source: TIter) -> Result<(), Error<Rule>>
     where
         TIter: Iterator<Item = String>,
     {
@@ -41,53 +54,56 @@ where
 
         source.for_each(|part| schema.push_str(&part));
 
-        let namespaces = parse(&schema)?;
-
-        self.schema = Schema::new(namespaces);
-
-        Ok(())
-    }
-
-    pub fn query<'a>(&'a self, query: &str) -> Result<Vec<&'a Point>, String> {
-        let query = match QuerySet::from_string(query.into()) {
-            Ok(q) => q,
-            Err(e) => return Err(e.to_string()),
+        let namespaces = match parse(&schema) {
+            Ok(namespaces) => namespaces,
+            Err(e) => {
+                counter!("rmber_schema_rebuilds_total", "result" => "error").increment(1);
+                return Err(e);
+            }
         };
 
-        let result = self.schema
-            .points()
-            .filter(|p| query.matches(&p.full_name))
-            .collect();
-
-        Ok(result)
-    }
+        *self.schema.write().unwrap() = Arc::new(Schema::new(namespaces));
+        counter!("rmber_schema_rebuilds_total", "result" => "ok").increment(1);
 
-    pub fn query_single<'a>(&'a self, query: &str) -> Option<&'a Point> {
-        // TODO: If the iterative search becomes too slow consider using some tree/binary-search based container.
-        self.schema.points().find(|p| p.full_name.eq(query))
+        Ok(())
     }
 
-    pub async fn update_point(&mut self, key: &StringKey, new_value: Value) -> Result<Value, std::io::Error> {
+    pub async fn update_point(&self, key: &StringKey, new_value: Value) -> Result<Value, std::io::Error> {
         use std::io::{Error, ErrorKind};
 
-        let point = match self.query_single(key.as_str()) {
-            Some(p) => p,
+        let valid = match self.schema().get(key.as_str()) {
+            Some(p) => p.types.contains(&to_point_type(&new_value)),
             None => return Err(Error::new(ErrorKind::NotFound, "Invalid point.")),
         };
 
-        let value_type = to_point_type(&new_value);
-
-        if !point.types.contains(&value_type) {
+        if !valid {
             return Err(Error::new(ErrorKind::InvalidData, "Invalid point-type."));
         }
 
+        let started = Instant::now();
         self.store.store_value(key, &new_value).await?;
+        histogram!("rmber_store_write_seconds").record(started.elapsed());
 
         Ok(new_value)
     }
+
+    pub async fn flush(&self) -> Result<(), std::io::Error> {
+        self.store.flush().await
+    }
+
+    /// Returns `None` for points that are part of the schema but were never written.
+    pub async fn get_point(&self, key: &StringKey) -> Result<Option<Value>, std::io::Error> {
+        use std::io::{Error, ErrorKind};
+
+        if self.schema().get(key.as_str()).is_none() {
+            return Err(Error::new(ErrorKind::NotFound, "Invalid point."));
+        }
+
+        Ok(self.store.get_value(key).await)
+    }
 }
 
-fn to_point_type(value: &Value) -> PointType {
+pub fn to_point_type(value: &Value) -> PointType {
     match value {
         Value::Boolean(_) => PointType::Boolean,
         Value::Blob(_) => PointType::Blob,
@@ -1,51 +1,201 @@
 use async_trait::async_trait;
-use std::io::{Cursor, Error, ErrorKind};
+use bytes::Bytes;
+use metrics::histogram;
+use std::io::{Cursor, Error};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use tokio::sync::oneshot;
 
-use protocol::{Key, Value};
 use crate::ValueStore;
+use protocol::{Key, Value};
+use rocksdb::{WriteBatch, DB};
 
 use super::Store;
 
-pub use rocksdb::DB;
+/// Writes committed in one batch at most, so a long queue doesn't delay every writer in it.
+const MAX_BATCH: usize = 1024;
 
-#[async_trait(?Send)]
-impl<TKey> Store<TKey> for DB
-    where
-        TKey: Key + 'static
-{
-    async fn store_value(&mut self, key: &TKey, value: &Value) -> Result<(), Error> {
-        let mut data = vec![];
-        let mut cursor = Cursor::new(&mut data);
+enum Command {
+    Put(Vec<u8>, Bytes, oneshot::Sender<Result<(), Error>>),
+    /// Answered after everything queued before it is written and flushed.
+    Flush(oneshot::Sender<Result<(), Error>>),
+}
+
+/// Keeps RocksDB off the async runtime, so a stalled write doesn't hold up network I/O.
+/// Writes are queued for a writer-thread, which commits all that are waiting in one batch.
+/// Reads run on tokio's blocking pool.
+pub struct RocksStore {
+    db: Arc<DB>,
+    /// Only taken on drop, to stop the writer.
+    commands: Option<Sender<Command>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl RocksStore {
+    pub fn new(db: DB) -> Self {
+        let db = Arc::new(db);
+        let (commands, queued) = channel();
 
-        value.write_to(&mut cursor).await?;
+        let writer = {
+            let db = db.clone();
 
-        match self.put(key.as_slice(), data) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(convert_err(e))
+            thread::Builder::new()
+                .name(String::from("store-writer"))
+                .spawn(move || write_loop(&db, queued))
+                .unwrap()
+        };
+
+        RocksStore {
+            db,
+            commands: Some(commands),
+            writer: Some(writer),
+        }
+    }
+
+    fn send(&self, command: Command) -> Result<(), Error> {
+        match &self.commands {
+            Some(commands) => commands.send(command).map_err(|_| stopped()),
+            None => Err(stopped()),
         }
     }
+}
+
+impl Drop for RocksStore {
+    fn drop(&mut self) {
+        // The writer finishes what is queued and exits once the queue is closed.
+        self.commands.take();
 
-    async fn get_value(&mut self, key: &TKey) -> Option<Value> {
-        match self.get_pinned(key.as_slice()) {
-            Ok(data) => match data {
-                Some(data) => {
-                    let mut cursor = Cursor::new(data);
-                    
-                    Value::read_from(&mut cursor).await.ok()
-                },
-                None => None,
-            },
-            Err(_) => None,
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
         }
     }
 }
 
-pub fn create_rocksdb(path: &str) -> ValueStore<DB> {
-    let db = DB::open_default(path).unwrap();
+#[async_trait]
+impl<TKey> Store<TKey> for RocksStore
+where
+    TKey: Key + Sync + 'static,
+{
+    async fn store_value(&self, key: &TKey, value: &Value) -> Result<(), Error> {
+        let (done, result) = oneshot::channel();
+
+        self.send(Command::Put(key.as_slice().to_vec(), value.as_span(), done))?;
+
+        result.await.unwrap_or_else(|_| Err(stopped()))
+    }
+
+    async fn get_value(&self, key: &TKey) -> Option<Value> {
+        let db = self.db.clone();
+        let key = key.as_slice().to_vec();
+
+        let data = match tokio::task::spawn_blocking(move || db.get(key)).await {
+            Ok(Ok(Some(data))) => data,
+            _ => return None,
+        };
+
+        Value::read_from(&mut Cursor::new(data)).await.ok()
+    }
+
+    /// Waits until the writer got to it.
+    async fn flush(&self) -> Result<(), Error> {
+        let (done, result) = oneshot::channel();
+
+        self.send(Command::Flush(done))?;
+
+        result.await.unwrap_or_else(|_| Err(stopped()))
+    }
+}
+
+pub fn create_rocksdb<P: AsRef<Path>>(path: P) -> Result<ValueStore<RocksStore>, Error> {
+    let db = DB::open_default(path).map_err(convert_err)?;
+
+    Ok(ValueStore::new(RocksStore::new(db)))
+}
+
+/// Waits for a write, then commits it together with all that were queued in the meantime.
+fn write_loop(db: &DB, queued: Receiver<Command>) {
+    while let Ok(first) = queued.recv() {
+        let mut batch = WriteBatch::default();
+        let mut waiting = Vec::new();
+        let mut flush = None;
+        let mut next = Some(first);
+
+        while let Some(command) = next.take() {
+            match command {
+                Command::Put(key, value, done) => {
+                    batch.put(key, value);
+                    waiting.push(done);
+                }
+                Command::Flush(done) => {
+                    flush = Some(done);
+                    break;
+                }
+            }
+
+            if waiting.len() < MAX_BATCH {
+                next = queued.try_recv().ok();
+            }
+        }
+
+        if !waiting.is_empty() {
+            histogram!("rmber_store_batch_writes").record(waiting.len() as f64);
+
+            let result = db.write(batch).map_err(|e| e.into_string());
+
+            for done in waiting {
+                // The value is stored even if its writer stopped waiting for it.
+                let _ = done.send(result.clone().map_err(Error::other));
+            }
+        }
+
+        if let Some(done) = flush {
+            let _ = done.send(db.flush().map_err(convert_err));
+        }
+    }
+}
 
-    ValueStore::new(db)
+fn stopped() -> Error {
+    Error::other("The store-writer has stopped.")
 }
 
 fn convert_err(err: rocksdb::Error) -> Error {
-    Error::new(ErrorKind::Other, err.to_string())
+    Error::other(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::StringKey;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writes_are_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(RocksStore::new(DB::open_default(dir.path()).unwrap()));
+        let keys: Vec<_> = (0..64)
+            .map(|i| StringKey::new(&format!("plant/p{}", i)).unwrap())
+            .collect();
+
+        let writes: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                let store = store.clone();
+                let key = key.clone();
+
+                tokio::spawn(async move { store.store_value(&key, &Value::I32(i as i32)).await })
+            })
+            .collect();
+
+        for write in writes {
+            write.await.unwrap().unwrap();
+        }
+
+        Store::<StringKey>::flush(&*store).await.unwrap();
+
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(store.get_value(key).await, Some(Value::I32(i as i32)));
+        }
+    }
 }